@@ -0,0 +1,292 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::SafeStoreError;
+use crate::storage::encoding::{Reader, Writer};
+
+// Source of the current time in seconds, injectable so that the lockout policy can be exercised without waiting
+pub trait Clock: Debug {
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+// Clock that only moves when told to, clones share the same time
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Rc<Cell<u64>>);
+
+impl ManualClock {
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock(Rc::new(Cell::new(now)))
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.set(self.0.get() + seconds);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+// All durations are in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimiterPolicy {
+    // Failed attempts allowed before any delay is imposed
+    pub free_attempts: u32,
+    // Delay after the first failure past the free attempts, doubled for every further failure
+    pub base_delay: u64,
+    pub max_delay: u64,
+    // Failed attempts after which the username or client is locked out
+    pub lockout_threshold: u32,
+    pub lockout_duration: u64,
+    // Failures older than this are forgotten
+    pub reset_after: u64,
+}
+
+impl Default for LimiterPolicy {
+    fn default() -> Self {
+        LimiterPolicy {
+            free_attempts: 3,
+            base_delay: 1,
+            max_delay: 60,
+            lockout_threshold: 10,
+            lockout_duration: 15 * 60,
+            reset_after: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct AttemptState {
+    failures: u32,
+    last_failure: u64,
+    blocked_until: u64,
+}
+
+// Tracks failed logins per username and per client identifier (e.g. an IP address).
+// Usernames are tracked whether or not the account exists, so the limiter does not reveal which ones do.
+#[derive(Debug, Default)]
+pub struct LoginLimiter {
+    pub policy: LimiterPolicy,
+    usernames: BTreeMap<Vec<u8>, AttemptState>,
+    clients: BTreeMap<Vec<u8>, AttemptState>,
+}
+
+impl LoginLimiter {
+    pub fn new(policy: LimiterPolicy) -> LoginLimiter {
+        LoginLimiter {
+            policy,
+            usernames: BTreeMap::new(),
+            clients: BTreeMap::new(),
+        }
+    }
+
+    pub fn check(&self, username: &[u8], client: &[u8], now: u64) -> Result<(), SafeStoreError> {
+        let blocked_until = [self.usernames.get(username), self.clients.get(client)]
+            .into_iter()
+            .flatten()
+            .filter(|state| !self.is_expired(state, now))
+            .map(|state| state.blocked_until)
+            .max()
+            .unwrap_or(0);
+        if blocked_until > now {
+            return Err(SafeStoreError::RateLimited { retry_after: blocked_until - now });
+        }
+        Ok(())
+    }
+
+    pub fn record_failure(&mut self, username: &[u8], client: &[u8], now: u64) {
+        self.prune(now);
+        let policy = self.policy.clone();
+        for state in [
+            self.usernames.entry(username.to_vec()).or_default(),
+            self.clients.entry(client.to_vec()).or_default(),
+        ] {
+            if now.saturating_sub(state.last_failure) >= policy.reset_after {
+                state.failures = 0;
+            }
+            state.failures += 1;
+            state.last_failure = now;
+            state.blocked_until = now.saturating_add(LoginLimiter::delay(&policy, state.failures));
+        }
+    }
+
+    // Only the username is cleared, otherwise logging into one's own account would reset a guessing client
+    pub fn record_success(&mut self, username: &[u8]) {
+        self.usernames.remove(username);
+    }
+
+    fn delay(policy: &LimiterPolicy, failures: u32) -> u64 {
+        if failures >= policy.lockout_threshold {
+            return policy.lockout_duration;
+        }
+        if failures <= policy.free_attempts {
+            return 0;
+        }
+        let doublings = failures - policy.free_attempts - 1;
+        policy.base_delay
+            .saturating_mul(1u64.checked_shl(doublings).unwrap_or(u64::MAX))
+            .min(policy.max_delay)
+    }
+
+    fn is_expired(&self, state: &AttemptState, now: u64) -> bool {
+        now >= state.blocked_until && now.saturating_sub(state.last_failure) >= self.policy.reset_after
+    }
+
+    fn prune(&mut self, now: u64) {
+        let policy = &self.policy;
+        let keep = |state: &AttemptState| now < state.blocked_until || now.saturating_sub(state.last_failure) < policy.reset_after;
+        self.usernames.retain(|_, state| keep(state));
+        self.clients.retain(|_, state| keep(state));
+    }
+
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_u32(self.policy.free_attempts);
+        writer.write_u64(self.policy.base_delay);
+        writer.write_u64(self.policy.max_delay);
+        writer.write_u32(self.policy.lockout_threshold);
+        writer.write_u64(self.policy.lockout_duration);
+        writer.write_u64(self.policy.reset_after);
+        for attempts in [&self.usernames, &self.clients] {
+            writer.write_u64(attempts.len() as u64);
+            for (key, state) in attempts {
+                writer.write_bytes(key);
+                writer.write_u32(state.failures);
+                writer.write_u64(state.last_failure);
+                writer.write_u64(state.blocked_until);
+            }
+        }
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<LoginLimiter, SafeStoreError> {
+        let policy = LimiterPolicy {
+            free_attempts: reader.read_u32()?,
+            base_delay: reader.read_u64()?,
+            max_delay: reader.read_u64()?,
+            lockout_threshold: reader.read_u32()?,
+            lockout_duration: reader.read_u64()?,
+            reset_after: reader.read_u64()?,
+        };
+        let mut limiter = LoginLimiter::new(policy);
+        for attempts in [&mut limiter.usernames, &mut limiter.clients] {
+            for _ in 0..reader.read_u64()? {
+                let key = reader.read_bytes()?;
+                let state = AttemptState {
+                    failures: reader.read_u32()?,
+                    last_failure: reader.read_u64()?,
+                    blocked_until: reader.read_u64()?,
+                };
+                attempts.insert(key, state);
+            }
+        }
+        Ok(limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_after(limiter: &LoginLimiter, username: &[u8], client: &[u8], clock: &ManualClock) -> u64 {
+        match limiter.check(username, client, clock.now()) {
+            Ok(()) => 0,
+            Err(SafeStoreError::RateLimited { retry_after }) => retry_after,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_past_the_free_attempts() {
+        let clock = ManualClock::new(1_000);
+        let mut limiter = LoginLimiter::new(LimiterPolicy::default());
+        let mut delays = Vec::new();
+        for _ in 0..9 {
+            limiter.record_failure(b"Alice", b"client", clock.now());
+            delays.push(retry_after(&limiter, b"Alice", b"client", &clock));
+        }
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4, 8, 16, 32]);
+
+        // Both the username and the client are held back
+        assert_eq!(retry_after(&limiter, b"Alice", b"other client", &clock), 32);
+        assert_eq!(retry_after(&limiter, b"Bob", b"client", &clock), 32);
+        clock.advance(32);
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 0);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let clock = ManualClock::new(1_000);
+        let mut limiter = LoginLimiter::new(LimiterPolicy { max_delay: 5, ..LimiterPolicy::default() });
+        for _ in 0..9 {
+            limiter.record_failure(b"Alice", b"client", clock.now());
+        }
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 5);
+    }
+
+    #[test]
+    fn too_many_failures_lock_out() {
+        let clock = ManualClock::new(1_000);
+        let policy = LimiterPolicy::default();
+        let mut limiter = LoginLimiter::new(policy.clone());
+        for _ in 0..policy.lockout_threshold {
+            limiter.record_failure(b"Alice", b"client", clock.now());
+        }
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), policy.lockout_duration);
+        clock.advance(policy.lockout_duration - 1);
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 1);
+        clock.advance(1);
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 0);
+
+        // The count was kept, the next failure locks out again
+        limiter.record_failure(b"Alice", b"client", clock.now());
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), policy.lockout_duration);
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_the_window() {
+        let clock = ManualClock::new(1_000);
+        let policy = LimiterPolicy::default();
+        let mut limiter = LoginLimiter::new(policy.clone());
+        for _ in 0..6 {
+            limiter.record_failure(b"Alice", b"client", clock.now());
+        }
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 4);
+
+        clock.advance(policy.reset_after);
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 0);
+        // Counted as a first failure again
+        limiter.record_failure(b"Alice", b"client", clock.now());
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 0);
+        assert!(limiter.usernames.values().all(|state| state.failures == 1));
+    }
+
+    #[test]
+    fn success_resets_the_username_only() {
+        let clock = ManualClock::new(1_000);
+        let mut limiter = LoginLimiter::new(LimiterPolicy::default());
+        for _ in 0..5 {
+            limiter.record_failure(b"Alice", b"client", clock.now());
+        }
+        limiter.record_success(b"Alice");
+        assert_eq!(retry_after(&limiter, b"Alice", b"other client", &clock), 0);
+        // The client that made the failed attempts is still held back
+        assert_eq!(retry_after(&limiter, b"Alice", b"client", &clock), 2);
+
+        clock.advance(2);
+        limiter.record_failure(b"Alice", b"other client", clock.now());
+        assert_eq!(retry_after(&limiter, b"Alice", b"other client", &clock), 0);
+    }
+}
@@ -0,0 +1,184 @@
+use dryoc::classic::crypto_box::{crypto_box_keypair, crypto_box_keypair_inplace, crypto_box_seed_keypair_inplace, PublicKey, SecretKey};
+use dryoc::classic::crypto_core::crypto_scalarmult;
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::cryptography::{derive_key, PasswordHash, LOGIN_KEY_INFO};
+use crate::error::SafeStoreError;
+
+// Augmented PAKE used to log in, a 3DH key exchange in the style of OPAQUE's AKE.
+// The client derives a static key pair from its password hash, the server only stores the public half,
+// so what the server keeps is not enough to log in as the user.
+// Both sides contribute fresh ephemeral keys and nonces, so a recorded transcript cannot be replayed.
+// The server only answers with a MAC once the client has proven knowledge of the password,
+// otherwise anyone could start a login and test password guesses offline against it.
+
+const PROTOCOL_LABEL: &[u8] = b"safestore login v1";
+pub const MAC_LEN: usize = 32;
+pub const NONCE_LEN: usize = 32;
+
+pub type Mac = [u8; MAC_LEN];
+
+// First message, client to server
+#[derive(Debug, Clone)]
+pub struct LoginStart {
+    pub client_nonce: [u8; NONCE_LEN],
+    pub client_ephemeral: PublicKey,
+}
+
+// Server answer to LoginStart, login_id identifies the pending login on the server
+#[derive(Debug, Clone)]
+pub struct LoginChallenge {
+    pub login_id: Uuid,
+    pub server_nonce: [u8; NONCE_LEN],
+    pub server_ephemeral: PublicKey,
+}
+
+// Last client message, proves knowledge of the password
+#[derive(Debug, Clone)]
+pub struct LoginFinish {
+    pub login_id: Uuid,
+    pub client_mac: Mac,
+}
+
+// The static login key pair of a user, derived from the password hash
+pub fn login_keypair(password_hash: &PasswordHash) -> Result<(PublicKey, Zeroizing<SecretKey>), SafeStoreError> {
+    let seed = derive_key(password_hash.as_bytes(), LOGIN_KEY_INFO)?;
+    let mut keypair = (PublicKey::default(), Zeroizing::new(SecretKey::default()));
+    crypto_box_seed_keypair_inplace(&mut keypair.0, &mut keypair.1, seed.as_slice());
+    Ok(keypair)
+}
+
+pub struct ClientLogin {
+    username: Vec<u8>,
+    start: LoginStart,
+    ephemeral_sk: Zeroizing<SecretKey>,
+    expected_server_mac: Option<Mac>,
+}
+
+impl ClientLogin {
+    pub fn start(username: &[u8]) -> (ClientLogin, LoginStart) {
+        let (mut client_ephemeral, mut ephemeral_sk) = (PublicKey::default(), Zeroizing::new(SecretKey::default()));
+        crypto_box_keypair_inplace(&mut client_ephemeral, &mut ephemeral_sk);
+        let start = LoginStart {
+            client_nonce: rand::random(),
+            client_ephemeral,
+        };
+        let login = ClientLogin {
+            username: username.to_vec(),
+            start: start.clone(),
+            ephemeral_sk,
+            expected_server_mac: None,
+        };
+        (login, start)
+    }
+
+    // server_public_key has to come from a trusted source, not from the server's answer
+    pub fn respond(&mut self, password_hash: &PasswordHash, server_public_key: &PublicKey, challenge: &LoginChallenge) -> Result<LoginFinish, SafeStoreError> {
+        let (client_pk, client_sk) = login_keypair(password_hash)?;
+        let ikm = Zeroizing::new([
+            diffie_hellman(&self.ephemeral_sk, &challenge.server_ephemeral)?,
+            diffie_hellman(&self.ephemeral_sk, server_public_key)?,
+            diffie_hellman(&client_sk, &challenge.server_ephemeral)?,
+        ]);
+        let transcript = Transcript { username: &self.username, start: &self.start, challenge, server_pk: server_public_key, client_pk: &client_pk };
+        let (client_mac, server_mac) = transcript.macs(&ikm)?;
+        self.expected_server_mac = Some(server_mac);
+        Ok(LoginFinish { login_id: challenge.login_id, client_mac })
+    }
+
+    // Checks that the server which accepted the login holds the server secret key
+    pub fn verify_server(&self, server_mac: &Mac) -> Result<(), SafeStoreError> {
+        let expected = self.expected_server_mac.ok_or(SafeStoreError::AuthenticationFailed)?;
+        if bool::from(expected.ct_eq(server_mac)) {
+            Ok(())
+        } else {
+            Err(SafeStoreError::AuthenticationFailed)
+        }
+    }
+}
+
+pub struct ServerLogin {
+    expected_client_mac: Mac,
+    server_mac: Mac,
+}
+
+impl ServerLogin {
+    pub fn respond(server_keypair: &(PublicKey, SecretKey), client_pk: &PublicKey, username: &[u8], start: &LoginStart) -> Result<(ServerLogin, LoginChallenge), SafeStoreError> {
+        let (server_ephemeral, ephemeral_sk) = crypto_box_keypair();
+        let challenge = LoginChallenge {
+            login_id: Uuid::new_v4(),
+            server_nonce: rand::random(),
+            server_ephemeral,
+        };
+        let ikm = [
+            diffie_hellman(&ephemeral_sk, &start.client_ephemeral)?,
+            diffie_hellman(&server_keypair.1, &start.client_ephemeral)?,
+            diffie_hellman(&ephemeral_sk, client_pk)?,
+        ];
+        let transcript = Transcript { username, start, challenge: &challenge, server_pk: &server_keypair.0, client_pk };
+        let (expected_client_mac, server_mac) = transcript.macs(&ikm)?;
+        Ok((ServerLogin { expected_client_mac, server_mac }, challenge))
+    }
+
+    // Returns the MAC the client uses to authenticate the server
+    pub fn finish(&self, finish: &LoginFinish) -> Result<Mac, SafeStoreError> {
+        if bool::from(self.expected_client_mac.ct_eq(&finish.client_mac)) {
+            Ok(self.server_mac)
+        } else {
+            Err(SafeStoreError::AuthenticationFailed)
+        }
+    }
+}
+
+struct Transcript<'a> {
+    username: &'a [u8],
+    start: &'a LoginStart,
+    challenge: &'a LoginChallenge,
+    server_pk: &'a PublicKey,
+    client_pk: &'a PublicKey,
+}
+
+impl Transcript<'_> {
+    fn macs(&self, ikm: &[[u8; 32]; 3]) -> Result<(Mac, Mac), SafeStoreError> {
+        let mut input = PROTOCOL_LABEL.to_vec();
+        input.extend_from_slice(&(self.username.len() as u64).to_le_bytes());
+        input.extend_from_slice(self.username);
+        input.extend_from_slice(&self.start.client_nonce);
+        input.extend_from_slice(&self.start.client_ephemeral);
+        input.extend_from_slice(self.challenge.login_id.as_bytes());
+        input.extend_from_slice(&self.challenge.server_nonce);
+        input.extend_from_slice(&self.challenge.server_ephemeral);
+        input.extend_from_slice(self.server_pk);
+        input.extend_from_slice(self.client_pk);
+        let mut transcript_hash = [0u8; 64];
+        generichash(&mut transcript_hash, &input, None)?;
+
+        let mut key_material = ikm.concat();
+        key_material.extend_from_slice(&transcript_hash);
+        let mut prk = [0u8; 64];
+        generichash(&mut prk, &key_material, None)?;
+
+        let mut client_mac = [0u8; MAC_LEN];
+        let mut server_mac = [0u8; MAC_LEN];
+        generichash(&mut client_mac, &[b"client mac".as_slice(), &transcript_hash].concat(), Some(&prk))?;
+        generichash(&mut server_mac, &[b"server mac".as_slice(), &transcript_hash].concat(), Some(&prk))?;
+        Ok((client_mac, server_mac))
+    }
+}
+
+fn generichash(output: &mut [u8], input: &[u8], key: Option<&[u8]>) -> Result<(), SafeStoreError> {
+    crypto_generichash(output, input, key).map_err(|err| SafeStoreError::Malformed(err.to_string()))
+}
+
+fn diffie_hellman(secret_key: &SecretKey, public_key: &PublicKey) -> Result<[u8; 32], SafeStoreError> {
+    let mut shared = [0u8; 32];
+    crypto_scalarmult(&mut shared, secret_key, public_key);
+    // Low order points give an all zero secret that an attacker could predict
+    if bool::from(shared.ct_eq(&[0u8; 32])) {
+        return Err(SafeStoreError::AuthenticationFailed);
+    }
+    Ok(shared)
+}
@@ -1 +1,4 @@
-pub mod user;
\ No newline at end of file
+pub mod limiter;
+pub mod login;
+pub mod token;
+pub mod user;
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use uuid::Uuid;
+
+use crate::error::SafeStoreError;
+
+pub const TOKEN_LEN: usize = 32;
+
+// Random bearer token handed out by a successful login, Debug does not print it
+pub struct SessionToken([u8; TOKEN_LEN]);
+
+impl SessionToken {
+    pub fn from_bytes(bytes: [u8; TOKEN_LEN]) -> SessionToken {
+        SessionToken(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; TOKEN_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionToken(..)")
+    }
+}
+
+#[derive(Debug)]
+struct SessionEntry {
+    user_id: Uuid,
+    created: u64,
+    last_used: u64,
+}
+
+// Server side table of open sessions, durations in seconds.
+// Sessions are looked up by a hash of the token, so the table alone is not enough to use a session.
+#[derive(Debug)]
+pub struct SessionTable {
+    pub idle_timeout: u64,
+    pub absolute_timeout: u64,
+    sessions: BTreeMap<[u8; 32], SessionEntry>,
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        SessionTable::new(15 * 60, 8 * 60 * 60)
+    }
+}
+
+impl SessionTable {
+    pub fn new(idle_timeout: u64, absolute_timeout: u64) -> SessionTable {
+        SessionTable {
+            idle_timeout,
+            absolute_timeout,
+            sessions: BTreeMap::new(),
+        }
+    }
+
+    pub fn create(&mut self, user_id: Uuid, now: u64) -> Result<SessionToken, SafeStoreError> {
+        self.prune(now);
+        let token = SessionToken(rand::random());
+        self.sessions.insert(SessionTable::lookup_key(&token)?, SessionEntry { user_id, created: now, last_used: now });
+        Ok(token)
+    }
+
+    // Returns the user the session belongs to and counts as activity for the idle timeout
+    pub fn authenticate(&mut self, token: &SessionToken, now: u64) -> Result<Uuid, SafeStoreError> {
+        let key = SessionTable::lookup_key(token)?;
+        let entry = self.sessions.get(&key).ok_or(SafeStoreError::InvalidSession)?;
+        if self.is_expired(entry, now) {
+            self.sessions.remove(&key);
+            return Err(SafeStoreError::InvalidSession);
+        }
+        let entry = self.sessions.get_mut(&key).ok_or(SafeStoreError::InvalidSession)?;
+        entry.last_used = now;
+        Ok(entry.user_id)
+    }
+
+    pub fn revoke(&mut self, token: &SessionToken) -> Result<(), SafeStoreError> {
+        self.sessions.remove(&SessionTable::lookup_key(token)?);
+        Ok(())
+    }
+
+    // Every session of the user except the one identified by keep
+    pub fn revoke_others(&mut self, user_id: Uuid, keep: &SessionToken) -> Result<(), SafeStoreError> {
+        let keep = SessionTable::lookup_key(keep)?;
+        self.sessions.retain(|key, entry| entry.user_id != user_id || *key == keep);
+        Ok(())
+    }
+
+    fn is_expired(&self, entry: &SessionEntry, now: u64) -> bool {
+        now.saturating_sub(entry.last_used) >= self.idle_timeout || now.saturating_sub(entry.created) >= self.absolute_timeout
+    }
+
+    fn prune(&mut self, now: u64) {
+        let sessions = std::mem::take(&mut self.sessions);
+        self.sessions = sessions.into_iter().filter(|(_, entry)| !self.is_expired(entry, now)).collect();
+    }
+
+    fn lookup_key(token: &SessionToken) -> Result<[u8; 32], SafeStoreError> {
+        let mut key = [0u8; 32];
+        crypto_generichash(&mut key, &token.0, None).map_err(|err| SafeStoreError::Malformed(err.to_string()))?;
+        Ok(key)
+    }
+}
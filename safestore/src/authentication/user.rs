@@ -1,24 +1,105 @@
+use std::fmt;
+
 use uuid::Uuid;
 use dryoc::sign::SigningKeyPair;
 use dryoc::types::StackByteArray;
 use dryoc::classic::crypto_box::*;
+use zeroize::Zeroizing;
+
+use crate::cryptography::{self, AssociatedData, Field, MasterKey};
+use crate::error::SafeStoreError;
+use crate::storage::encoding::{Reader, Writer};
 
 
+// The account as the server stores it: only the public halves of the user's key pairs are in clear.
+// The secret halves are generated and kept by the client, the server only holds them wrapped under the master key, see UserKeys.
 #[derive(Debug)]
 pub struct User {
     pub id: Uuid,
     pub name: Vec<u8>,
+    pub signing_public_key: [u8; 32],
+    pub public_key: PublicKey,
+    // UserKeys::wrap output
+    pub enc_keys: Vec<u8>,
+}
+
+// Secret halves of a user's key pairs, they never leave the client unwrapped and are wiped when dropped
+pub struct UserKeys {
     pub signing_keypair: SigningKeyPair<StackByteArray<32>, StackByteArray<64>>,
-    pub keypair: (PublicKey, SecretKey),
+    pub keypair: (PublicKey, Zeroizing<SecretKey>),
+}
+
+impl fmt::Debug for UserKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserKeys(..)")
+    }
+}
+
+impl UserKeys {
+    pub fn generate() -> UserKeys {
+        let mut keypair = (PublicKey::default(), Zeroizing::new(SecretKey::default()));
+        crypto_box_keypair_inplace(&mut keypair.0, &mut keypair.1);
+        UserKeys {
+            signing_keypair: SigningKeyPair::gen_with_defaults(),
+            keypair,
+        }
+    }
+
+    // Bound to the user id, so that one user's wrapped keys cannot be handed to another
+    pub fn wrap(&self, master_key: &MasterKey, user_id: Uuid) -> Result<Vec<u8>, SafeStoreError> {
+        let mut writer = Writer::new();
+        self.encode(&mut writer);
+        let plaintext = Zeroizing::new(writer.into_bytes());
+        cryptography::symmetric_encrypt(master_key.as_bytes(), &plaintext, &AssociatedData::new(user_id, Field::UserKeys, None))
+    }
+
+    pub fn unwrap(master_key: &MasterKey, enc_keys: &[u8], user_id: Uuid) -> Result<UserKeys, SafeStoreError> {
+        let plaintext = Zeroizing::new(cryptography::symmetric_decrypt(master_key.as_bytes(), enc_keys, &AssociatedData::new(user_id, Field::UserKeys, None))?);
+        let mut reader = Reader::new(&plaintext);
+        let keys = UserKeys::decode(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(SafeStoreError::Malformed("trailing data after user keys".to_string()));
+        }
+        Ok(keys)
+    }
+
+    pub fn duplicate(&self) -> UserKeys {
+        UserKeys {
+            signing_keypair: SigningKeyPair {
+                public_key: self.signing_keypair.public_key.clone(),
+                secret_key: self.signing_keypair.secret_key.clone(),
+            },
+            keypair: (self.keypair.0, self.keypair.1.clone()),
+        }
+    }
+
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_bytes(&self.signing_keypair.public_key[..]);
+        writer.write_bytes(&self.signing_keypair.secret_key[..]);
+        writer.write_bytes(&self.keypair.0);
+        writer.write_bytes(&*self.keypair.1);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<UserKeys, SafeStoreError> {
+        let public_key: [u8; 32] = reader.read_array()?;
+        let secret_key: [u8; 64] = reader.read_array()?;
+        Ok(UserKeys {
+            signing_keypair: SigningKeyPair {
+                public_key: StackByteArray::from(public_key),
+                secret_key: StackByteArray::from(secret_key),
+            },
+            keypair: (reader.read_array()?, Zeroizing::new(reader.read_array()?)),
+        })
+    }
 }
 
 impl User {
-    pub fn factory(name: Option<Vec<u8>>) -> User {
+    pub fn factory(name: Option<Vec<u8>>, keys: &UserKeys) -> User {
         let name = match name {
             Some(n) => n,
             None => User::random_name().into_bytes(),
         };
-        User::new(name)
+        User::new(name, keys)
     }
 
     pub fn display_info(&self) -> String {
@@ -32,15 +113,37 @@ impl User {
         User::USERNAMES[index].to_string()
     }
     
-    fn new(name: Vec<u8>) -> User {
+    // enc_keys is left empty, the caller wraps keys once it has a master key
+    fn new(name: Vec<u8>, keys: &UserKeys) -> User {
+        let mut signing_public_key = [0u8; 32];
+        signing_public_key.copy_from_slice(&keys.signing_keypair.public_key[..]);
         User {
             id: Uuid::new_v4(),
             name,
-            signing_keypair: SigningKeyPair::gen_with_defaults(),
-            keypair: crypto_box_keypair(),
+            signing_public_key,
+            public_key: keys.keypair.0,
+            enc_keys: Vec::new(),
         }
     }
     
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_raw(self.id.as_bytes());
+        writer.write_bytes(&self.name);
+        writer.write_bytes(&self.signing_public_key);
+        writer.write_bytes(&self.public_key);
+        writer.write_bytes(&self.enc_keys);
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<User, SafeStoreError> {
+        Ok(User {
+            id: reader.read_uuid()?,
+            name: reader.read_bytes()?,
+            signing_public_key: reader.read_array()?,
+            public_key: reader.read_array()?,
+            enc_keys: reader.read_bytes()?,
+        })
+    }
+
     const USERNAMES: [&'static str; 16] = ["Alice", "Bob", "Charlie", "David", "Eve", "Frank", "Grace", "Heidi", "Ivan", "Judy", "Mallory", "Oscar", "Peggy", "Romeo", "Trent", "Walter"];
 }
 
@@ -0,0 +1,279 @@
+use safestore::authentication::limiter::{Clock, ManualClock, SystemClock};
+use safestore::authentication::login::{ClientLogin, LoginFinish};
+use safestore::storage::file::File;
+use safestore::storage::server::Server;
+use safestore::client::credentials::CredentialUpdate;
+use safestore::client::registration::register;
+use safestore::client::session::ClientSession;
+use safestore::cryptography::{decrypt_stream, encrypt_stream, hash_password, AssociatedData, Field, FileKey};
+use safestore::error::SafeStoreError;
+use safestore::sharing::registry::Capability;
+
+fn main() -> Result<(), SafeStoreError> {
+    print_title();
+    println!("-------------------------------------------------------------");
+    println!("Welcome to SafeStore, a secure file storage system");
+    println!("-------------------------------------------------------------");
+    println!();
+    let mut server = Server::new();
+    println!("[DEBUG] Creating Alice and Bob's accounts...");
+    let kdf_policy = server.kdf_policy;
+    let padding = server.padding;
+    server.register(register(b"Alice", b"password", &kdf_policy, padding)?)?;
+    server.register(register(b"Bob", b"password", &kdf_policy, padding)?)?;
+    
+    println!("[DEBUG] Alice and Bob's accounts have been created");
+    server.display_users();
+    println!("[DEBUG] Mallory tries to register as Alice");
+    if let Err(err) = server.register(register(b"Alice", b"123456", &kdf_policy, padding)?) {
+        println!("[DEBUG] Registration rejected: {}", err);
+    }
+    println!("[DEBUG] Mallory guesses Alice's password, then tries an account that does not exist");
+    let wrong_password = ClientSession::open(&mut server, b"Alice", b"123456").err().map(|err| format!("{:?}", err));
+    let unknown_user = ClientSession::open(&mut server, b"Zoe", b"123456").err().map(|err| format!("{:?}", err));
+    println!("[DEBUG] Wrong password: {:?}, unknown user: {:?}", wrong_password, unknown_user);
+    println!("[DEBUG] Both failures are indistinguishable: {}", wrong_password.is_some() && wrong_password == unknown_user);
+    println!("[DEBUG] Mallory keeps guessing Bob's password from 10.0.0.66");
+    let clock = ManualClock::new(SystemClock.now());
+    server.set_clock(Box::new(clock.clone()));
+    for guess in [&b"123456"[..], b"qwerty", b"letmein", b"dragon", b"monkey"] {
+        if let Err(err) = ClientSession::open_from(&mut server, b"10.0.0.66", b"Bob", guess) {
+            println!("[DEBUG] Guess rejected: {}", err);
+        }
+    }
+    println!("[DEBUG] Bob has to wait as well: {:?}", ClientSession::open(&mut server, b"Bob", b"password").err());
+    clock.advance(60);
+    ClientSession::open(&mut server, b"Bob", b"password")?.close()?;
+    println!("[DEBUG] A minute later Bob logs in again");
+    server.set_clock(Box::new(SystemClock));
+
+    println!("[DEBUG] Mallory records the messages of one of Alice's logins and replays them");
+    let (password_salt, kdf_params) = server.get_password_salt(b"Alice".to_vec())?;
+    let (password_hash, _) = hash_password(b"password", Some(&password_salt), &kdf_params)?;
+    let (mut login, start) = ClientLogin::start(b"Alice");
+    let challenge = server.login_start(b"10.0.0.1", b"Alice", start.clone())?;
+    let finish = login.respond(&password_hash, &server.public_key(), &challenge)?;
+    let response = server.login_finish(finish.clone())?;
+    println!("[DEBUG] Replaying the last message: {:?}", server.login_finish(finish.clone()).err());
+    let challenge = server.login_start(b"10.0.0.67", b"Alice", start)?;
+    let replayed = server.login_finish(LoginFinish { login_id: challenge.login_id, client_mac: finish.client_mac }).err();
+    println!("[DEBUG] Replaying the whole login against a fresh challenge: {:?}", replayed);
+    let partial = CredentialUpdate { login_key: Some(server.public_key()), ..CredentialUpdate::default() };
+    println!("[DEBUG] Changing only part of the credentials: {:?}", server.change_password(&response.token, finish, partial).err());
+    println!("[DEBUG] Alice logs out, which revokes the session token");
+    let etag = response.enc_root_folder.etag()?;
+    server.logout(&response.token, response.enc_root_folder.duplicate(), etag)?;
+    let reused = server.logout(&response.token, response.enc_root_folder, etag).err();
+    println!("[DEBUG] Reusing the token after logout: {:?}", reused);
+
+    println!("[DEBUG] Alice and Bob fill their root folders");
+    let mut session = ClientSession::open(&mut server, b"Alice", b"password")?;
+    session.root_mut().create_dir_all("/home")?;
+    session.root_mut().write_file("/home/myfile", b"Hello, World!".to_vec())?;
+    session.root_mut().write_file("/anotherfile", b"This is a file.".to_vec())?;
+    session.close()?;
+    let mut session = ClientSession::open(&mut server, b"Bob", b"password")?;
+    session.root_mut().write_file("/athirdfile", b"This is a file too.".to_vec())?;
+    session.close()?;
+    println!("[DEBUG] Alice and Bob's root folders have been created");
+    server.display_root_folders();
+    let sizes: Vec<(usize, usize)> = [13, 100, 1000, 1_000_000].iter().map(|&len| (len, padding.padded_len(len + 1))).collect();
+    println!("[DEBUG] Names and contents are padded with {:?} before encryption, (length, padded length): {:?}", padding, sizes);
+
+    println!("-------------------------------------------------------------");
+    println!("                    PERSISTENCE PROCEDURE                    ");
+    println!("-------------------------------------------------------------");
+    let store_path = std::env::temp_dir().join("safestore.db");
+    println!("[DEBUG] Saving the server state to {}", store_path.display());
+    server.save(&store_path)?;
+    println!("[DEBUG] Reloading the server state from disk");
+    let mut server = Server::load(&store_path)?;
+    server.display_users();
+
+    println!("-------------------------------------------------------------");
+    println!("                    TAMPERING PROCEDURE                      ");
+    println!("-------------------------------------------------------------");
+    // The server cannot decrypt anything, but it can rearrange the ciphertexts it stores
+    let alice_id = server.get_user(b"Alice")?.id;
+    let alice_root = server.root_folders.iter().position(|folder| folder.name == alice_id.as_bytes())
+        .ok_or_else(|| SafeStoreError::NotFound("Alice's root folder".to_string()))?;
+    let original = server.root_folders[alice_root].duplicate();
+
+    let file = &mut server.root_folders[alice_root].files[0];
+    std::mem::swap(&mut file.name, &mut *file.data);
+    println!("[DEBUG] The server swaps the name and content of one of Alice's files: {:?}", ClientSession::open(&mut server, b"Alice", b"password").err());
+
+    server.root_folders[alice_root] = original.duplicate();
+    let root = &mut server.root_folders[alice_root];
+    let file = root.files.remove(0);
+    let file_key = root.file_keys.remove(&file.id).ok_or(SafeStoreError::MissingKey)?;
+    root.folders[0].file_keys.insert(file.id, file_key);
+    root.folders[0].files.push(file);
+    println!("[DEBUG] The server moves one of Alice's files into another folder: {:?}", ClientSession::open(&mut server, b"Alice", b"password").err());
+
+    server.root_folders[alice_root] = original;
+    println!("[DEBUG] The server restores Alice's folder");
+
+    println!("-------------------------------------------------------------");
+    println!("                   LARGE FILE PROCEDURE                      ");
+    println!("-------------------------------------------------------------");
+    // Large contents are encrypted chunk by chunk, straight from and to disk
+    let plain_path = std::env::temp_dir().join("safestore-large.bin");
+    let sealed_path = std::env::temp_dir().join("safestore-large.sealed");
+    let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&plain_path, &contents)?;
+    let key = FileKey::generate()?;
+    let aad = AssociatedData::new(alice_id, Field::Data, None);
+    let length = encrypt_stream(key.as_bytes(), &aad, std::fs::File::open(&plain_path)?, std::fs::File::create(&sealed_path)?)?;
+    println!("[DEBUG] {} bytes encrypted to {}", length, sealed_path.display());
+    let mut decrypted = Vec::new();
+    decrypt_stream(key.as_bytes(), &aad, std::fs::File::open(&sealed_path)?, &mut decrypted)?;
+    println!("[DEBUG] Decrypted contents match: {}", decrypted == contents);
+    let sealed = std::fs::read(&sealed_path)?;
+    let truncated = decrypt_stream(key.as_bytes(), &aad, &sealed[..sealed.len() / 2], std::io::sink()).err();
+    println!("[DEBUG] Cutting the encrypted file in half: {:?}", truncated);
+    std::fs::remove_file(&plain_path)?;
+    std::fs::remove_file(&sealed_path)?;
+
+    println!("-------------------------------------------------------------");
+    println!("                      LOGIN PROCEDURE                        ");
+    println!("-------------------------------------------------------------");
+    println!("[DEBUG] The server raises its password hashing policy, Alice's password is rehashed at the next login");
+    server.kdf_policy.t_cost += 1;
+    println!("[DEBUG] Alice's parameters before: {:?}", server.get_password_salt(b"Alice".to_vec())?.1);
+    ClientSession::open(&mut server, b"Alice", b"password")?.close()?;
+    println!("[DEBUG] Alice's parameters after: {:?}", server.get_password_salt(b"Alice".to_vec())?.1);
+    println!("[DEBUG] Alice wants to log in...");
+    // Alice types in her password
+    let mut session = ClientSession::open(&mut server, b"Alice", b"password")?;
+    session.root_mut().add_file(File::factory(b"Alice"), FileKey::generate()?);
+    session.root_mut().create_dir_all("/home/reports")?;
+    session.root_mut().write_file("/home/reports/draft.txt", b"Quarterly report".to_vec())?;
+    session.root_mut().rename("/home/reports/draft.txt", "report.txt")?;
+    println!("{}", session.root().display(0));
+    
+    println!("-------------------------------------------------------------");
+    println!("                 CHANGE PASSWORD PROCEDURE                   ");
+    println!("-------------------------------------------------------------");
+    println!("[DEBUG] Alice wants to change her password...");
+    // Alice decides to change her password, the server wants the old one as well
+    println!("[DEBUG] A wrong old password is rejected: {:?}", session.change_password(b"123456", b"newpassword").err());
+    session.change_password(b"password", b"newpassword")?;
+    println!("[DEBUG] Alice's password has been changed");
+    session.close()?;
+    
+    println!("[DEBUG] Alice logs in again using her new password");
+    // Alice wants to log in again using her newly set password
+    let mut session = ClientSession::open(&mut server, b"Alice", b"newpassword")?;
+    
+    // Alice consults her root folder
+    println!("{}", session.root().display(0));
+
+    println!("-------------------------------------------------------------");
+    println!("                  SHARING FOLDER PROCEDURE                   ");
+    println!("-------------------------------------------------------------");
+    println!("[DEBUG] Alice wants to share the home folder with Bob, with write access, and the reports, read only...");
+    println!("[DEBUG] Alice seals the keys of the folders with Bob's public key and leaves them in Bob's inbox");
+    let home_share = session.share_folder("/home", b"Bob", Capability::Write)?;
+    let reports_share = session.share_folder("/home/reports", b"Bob", Capability::Read)?;
+    session.close()?;
+
+    println!("[DEBUG] Bob checks the inbox and accepts both folders");
+    let mut session = ClientSession::open(&mut server, b"Bob", b"password")?;
+    for share in session.incoming_shares()? {
+        println!("[DEBUG] Share {} of folder {} from {}, {:?}, {:?}", share.id, share.envelope.folder_id, String::from_utf8_lossy(&share.envelope.sender), share.status, share.capability);
+    }
+    let envelope = session.accept_share(home_share)?;
+    let reports_envelope = session.accept_share(reports_share)?;
+    println!("[DEBUG] Both folders are mounted under /shared in Bob's own tree");
+    println!("{}", session.root().display(0));
+    let (_, mut home) = session.open_link("/shared/Alice/home")?;
+    println!("{}", home.display(1));
+    println!("[DEBUG] Bob adds a file to the shared folder");
+    home.write_file("/notes.txt", b"Written by Bob".to_vec())?;
+    session.save_shared(&envelope, &home)?;
+
+    println!("[DEBUG] Bob tries to change the reports and to pass them on, both need more than read access");
+    let mut reports = session.open_shared(&reports_envelope)?;
+    reports.write_file("/report.txt", b"Rewritten by Bob".to_vec())?;
+    match session.save_shared(&reports_envelope, &reports) {
+        Err(err) => println!("[DEBUG] Saving the reports: {:?}", err),
+        Ok(_) => println!("[DEBUG] Bob saved the read only reports!"),
+    }
+    match session.reshare(&reports_envelope, b"Alice", Capability::Read) {
+        Err(err) => println!("[DEBUG] Sharing the reports on: {:?}", err),
+        Ok(_) => println!("[DEBUG] Bob shared the read only reports on!"),
+    }
+    let keys = session.open_envelope(&reports_envelope)?;
+    session.close()?;
+
+    println!("[DEBUG] Even if Bob got a modified version onto the server, it is not signed with the write key and honest clients reject it");
+    let forged = reports.symmetric_encrypt(keys.read_key.as_bytes(), None, Some(reports_envelope.parent_id), server.padding)?;
+    match forged.verify_with(&keys.verify_key) {
+        Err(err) => println!("[DEBUG] Checking Bob's version of the reports: {:?}", err),
+        Ok(_) => println!("[DEBUG] Bob's version of the reports passed the check!"),
+    }
+
+    println!("[DEBUG] Alice sees Bob's file in her own tree");
+    let mut session = ClientSession::open(&mut server, b"Alice", b"newpassword")?;
+    println!("{}", session.root().display(0));
+
+    println!("-------------------------------------------------------------");
+    println!("                 REVOKING SHARE PROCEDURE                    ");
+    println!("-------------------------------------------------------------");
+    println!("[DEBUG] Alice takes the home folder back from Bob, its keys are rotated");
+    session.revoke_share("/home", b"Bob")?;
+    session.root_mut().write_file("/home/after.txt", b"Written after the revocation".to_vec())?;
+    session.close()?;
+
+    println!("[DEBUG] Bob can no longer fetch the folder from the server, neither through the link nor with the envelope kept from before");
+    let mut session = ClientSession::open(&mut server, b"Bob", b"password")?;
+    match session.open_link("/shared/Alice/home") {
+        Err(err) => println!("[DEBUG] Bob cannot follow the link to the home folder: {:?}", err),
+        Ok(_) => println!("[DEBUG] Bob still followed the link to the home folder!"),
+    }
+    match session.open_shared(&envelope) {
+        Err(err) => println!("[DEBUG] Bob cannot open the home folder: {:?}", err),
+        Ok(_) => println!("[DEBUG] Bob still opened the home folder!"),
+    }
+    println!("[DEBUG] The reports are still shared with Bob, the link resolves to them under their new key");
+    let (_, reports) = session.open_link("/shared/Alice/reports")?;
+    println!("{}", reports.display(1));
+    let old_keys = session.open_envelope(&envelope)?;
+    session.close()?;
+
+    println!("[DEBUG] Even given the stored ciphertext, the key Bob was sent no longer decrypts it");
+    let enc_home = server.root_folders.iter().find_map(|root| root.find(envelope.folder_id)).ok_or(SafeStoreError::MissingKey)?;
+    match enc_home.symmetric_decrypt(old_keys.read_key.as_bytes(), old_keys.write_key.as_ref().map(|write_key| write_key.as_bytes()), Some(envelope.parent_id)) {
+        Err(err) => println!("[DEBUG] Decrypting with the old key: {:?}", err),
+        Ok(_) => println!("[DEBUG] The old key still decrypts the home folder!"),
+    }
+    Ok(())
+}
+
+pub fn print_title() {
+    let title_string = r" .----------------.  .----------------.  .----------------.  .----------------. ";
+    let title_string1 = r"| .--------------. || .--------------. || .--------------. || .--------------. |";
+    let title_string2 = r"| |    _______   | || |  _________   | || |    _______   | || |  _________   | |";
+    let title_string3 = r"| |   /  ___  |  | || | |_   ___  |  | || |   /  ___  |  | || | |  _   _  |  | |";
+    let title_string4 = r"| |  |  (__ \_|  | || |   | |_  \_|  | || |  |  (__ \_|  | || | |_/ | | \_|  | |";
+    let title_string5 = r"| |   '.___`-.   | || |   |  _|      | || |   '.___`-.   | || |     | |      | |";
+    let title_string6 = r"| |  |`\____) |  | || |  _| |_       | || |  |`\____) |  | || |    _| |_     | |";
+    let title_string7 = r"| |  |_______.'  | || | |_____|      | || |  |_______.'  | || |   |_____|    | |";
+    let title_string8 = r"| |              | || |              | || |              | || |              | |";
+    let title_string9 = r"| '--------------' || '--------------' || '--------------' || '--------------' |";
+    let title_string10 = r" '----------------'  '----------------'  '----------------'  '----------------' ";
+
+    println!("{}", title_string);
+    println!("{}", title_string1);
+    println!("{}", title_string2);
+    println!("{}", title_string3);
+    println!("{}", title_string4);
+    println!("{}", title_string5);
+    println!("{}", title_string6);
+    println!("{}", title_string7);
+    println!("{}", title_string8);
+    println!("{}", title_string9);
+    println!("{}", title_string10);
+    println!("By Titus Abele, 2024");
+}
\ No newline at end of file
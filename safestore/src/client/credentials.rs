@@ -0,0 +1,28 @@
+use dryoc::classic::crypto_box::PublicKey;
+use uuid::Uuid;
+
+use crate::authentication::login::login_keypair;
+use crate::cryptography::{hash_password, wrap_master_key, KdfParams, MasterKey, SaltString};
+use crate::error::SafeStoreError;
+
+// Replacement credentials for an existing account, derived on the client like a Registration.
+// The server only applies an update that replaces all four parts together.
+#[derive(Debug, Default)]
+pub struct CredentialUpdate {
+    pub password_salt: Option<SaltString>,
+    pub kdf_params: Option<KdfParams>,
+    pub login_key: Option<PublicKey>,
+    pub enc_master_key: Option<Vec<u8>>,
+}
+
+pub fn derive_credentials(user_id: Uuid, new_password: &[u8], master_key: &MasterKey, kdf_params: &KdfParams) -> Result<CredentialUpdate, SafeStoreError> {
+    let (password_hash, password_salt) = hash_password(new_password, None, kdf_params)?;
+    let (login_key, _) = login_keypair(&password_hash)?;
+    let enc_master_key = wrap_master_key(&password_hash, master_key, user_id)?;
+    Ok(CredentialUpdate {
+        password_salt: Some(password_salt),
+        kdf_params: Some(*kdf_params),
+        login_key: Some(login_key),
+        enc_master_key: Some(enc_master_key),
+    })
+}
@@ -0,0 +1,3 @@
+pub mod credentials;
+pub mod registration;
+pub mod session;
@@ -0,0 +1,48 @@
+use dryoc::classic::crypto_box::PublicKey;
+
+use crate::authentication::login::login_keypair;
+use crate::authentication::user::{User, UserKeys};
+use crate::cryptography::{hash_password, wrap_master_key, KdfParams, MasterKey, PaddingPolicy, SaltString};
+use crate::error::SafeStoreError;
+use crate::storage::folder::Folder;
+
+// Everything the server needs to store for a new account, derived on the client from the username and password.
+// Nothing secret is sent: the user only carries public keys, the secret ones are wrapped under the master key
+pub struct Registration {
+    pub user: User,
+    pub enc_master_key: Vec<u8>,
+    pub password_salt: SaltString,
+    pub kdf_params: KdfParams,
+    pub login_key: PublicKey,
+    pub enc_root_folder: Folder,
+}
+
+// kdf_params has to be at least the server's kdf_policy, padding is the server's padding policy
+pub fn register(username: &[u8], password: &[u8], kdf_params: &KdfParams, padding: PaddingPolicy) -> Result<Registration, SafeStoreError> {
+    if username.is_empty() {
+        return Err(SafeStoreError::Malformed("empty username".to_string()));
+    }
+    let keys = UserKeys::generate();
+    let mut user = User::factory(Some(username.to_vec()), &keys);
+
+    let (password_hash, password_salt) = hash_password(password, None, kdf_params)?;
+    let (login_key, _) = login_keypair(&password_hash)?;
+
+    let master_key = MasterKey::generate()?;
+    let enc_master_key = wrap_master_key(&password_hash, &master_key, user.id)?;
+    // Only the public keys go to the server in clear, the secret ones are sealed under the master key
+    user.enc_keys = keys.wrap(&master_key, user.id)?;
+
+    // The root folder is named after the user id so that the server can find it without decrypting anything
+    let root_folder = Folder::new(user.id.as_bytes().to_vec(), user.name.clone());
+    let enc_root_folder = root_folder.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, padding)?;
+
+    Ok(Registration {
+        user,
+        enc_master_key,
+        password_salt,
+        kdf_params: *kdf_params,
+        login_key,
+        enc_root_folder,
+    })
+}
@@ -0,0 +1,554 @@
+use crate::authentication::login::{ClientLogin, LoginFinish};
+use crate::authentication::token::SessionToken;
+use crate::authentication::user::{User, UserKeys};
+use crate::client::credentials::derive_credentials;
+use crate::cryptography::{self, hash_password, unwrap_master_key, KdfParams, MasterKey, PasswordHash, WriteKey};
+use crate::error::SafeStoreError;
+use crate::sharing::envelope::{FolderKeys, KeyEnvelope};
+use crate::sharing::registry::{Capability, Share, ShareStatus};
+use crate::storage::folder::{Entry, Folder};
+use crate::storage::link::Link;
+use crate::storage::server::Server;
+
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use uuid::Uuid;
+
+// Client side of the login/logout protocol.
+// The session keeps the decrypted root folder in memory and uploads it again, encrypted, when it is closed.
+// Dropping a session without calling close() discards every change made to the tree.
+pub struct ClientSession<'a> {
+    server: &'a mut Server,
+    client: Vec<u8>,
+    username: Vec<u8>,
+    token: SessionToken,
+    master_key: MasterKey,
+    // Secret halves of the user's key pairs, unwrapped with the master key at login
+    keys: UserKeys,
+    root: Folder,
+    // Etags of the stored versions root and the shared folders opened were made from, sent along with their uploads
+    root_etag: [u8; 32],
+    etags: BTreeMap<Uuid, [u8; 32]>,
+}
+
+// Returned by close when the tree could not be uploaded. The session is handed back still open, with the tree as it was,
+// e.g. to refresh it after a Conflict and close again.
+pub struct CloseError<'a> {
+    pub session: Box<ClientSession<'a>>,
+    pub error: SafeStoreError,
+}
+
+impl fmt::Debug for CloseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloseError").field("error", &self.error).finish_non_exhaustive()
+    }
+}
+
+impl From<CloseError<'_>> for SafeStoreError {
+    fn from(err: CloseError<'_>) -> SafeStoreError {
+        err.error
+    }
+}
+
+// Client identifier used when the session runs in the same process as the server
+pub const LOCAL_CLIENT: &[u8] = b"local";
+
+impl<'a> ClientSession<'a> {
+    pub fn open(server: &'a mut Server, username: &[u8], password: &[u8]) -> Result<ClientSession<'a>, SafeStoreError> {
+        ClientSession::open_from(server, LOCAL_CLIENT, username, password)
+    }
+
+    // client is the identifier the server rate limits failed logins by
+    pub fn open_from(server: &'a mut Server, client: &[u8], username: &[u8], password: &[u8]) -> Result<ClientSession<'a>, SafeStoreError> {
+        let (login, finish, password_hash) = ClientSession::prove_password(server, client, username, password)?;
+        let response = server.login_finish(finish)?;
+        login.verify_server(&response.server_mac)?;
+
+        let user = server.get_user(username)?;
+        let user_id = user.id;
+        let master_key = unwrap_master_key(&password_hash, &response.enc_master_key, user_id)?;
+        let keys = UserKeys::unwrap(&master_key, &user.enc_keys, user_id)?;
+        let root = response.enc_root_folder.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None)?;
+        let root_etag = response.enc_root_folder.etag()?;
+        let etags = ClientSession::shared_etags(&response.enc_root_folder)?;
+
+        let mut session = ClientSession {
+            server,
+            client: client.to_vec(),
+            username: username.to_vec(),
+            token: response.token,
+            master_key,
+            keys,
+            root,
+            root_etag,
+            etags: etags.into_iter().collect(),
+        };
+        // The password was hashed under an older, weaker policy: rehash the same password with the new parameters
+        if let Some(kdf_params) = response.kdf_upgrade {
+            session.rehash_password(password, &kdf_params)?;
+        }
+        Ok(session)
+    }
+
+    pub fn root(&self) -> &Folder {
+        &self.root
+    }
+
+    pub fn root_mut(&mut self) -> &mut Folder {
+        &mut self.root
+    }
+
+    pub fn user(&self) -> Result<&User, SafeStoreError> {
+        self.server.get_user(&self.username)
+    }
+
+    // Takes effect immediately, other sessions of the user are closed by the server
+    pub fn change_password(&mut self, old_password: &[u8], new_password: &[u8]) -> Result<(), SafeStoreError> {
+        let kdf_params = self.server.kdf_policy;
+        self.replace_password(old_password, new_password, &kdf_params)
+    }
+
+    // Offers the folder at path to recipient with the given capability, sealed so that only they can open it.
+    // Once they accept, they work on the same encrypted folder, see open_shared and save_shared.
+    // The first share of a folder gives it a write key, the tree is uploaded again, signed, before the share is created.
+    pub fn share_folder(&mut self, path: &str, recipient: &[u8], capability: Capability) -> Result<Uuid, SafeStoreError> {
+        let (folder_id, _, _) = self.root.folder_key(path)?;
+        let (parent_id, keys) = ClientSession::folder_keys(&mut self.root, folder_id, capability)?;
+        self.upload()?;
+        self.send_share(folder_id, parent_id, &keys, recipient, capability)
+    }
+
+    // Passes a folder shared with this user on to recipient, the server checks that the share allows it.
+    // Write and above can only be passed on by a user who was given the write key.
+    pub fn reshare(&mut self, envelope: &KeyEnvelope, recipient: &[u8], capability: Capability) -> Result<Uuid, SafeStoreError> {
+        let mut keys = self.open_envelope(envelope)?;
+        if capability < Capability::Write {
+            keys.write_key = None;
+        } else if keys.write_key.is_none() {
+            return Err(SafeStoreError::PermissionDenied);
+        }
+        self.send_share(envelope.folder_id, envelope.parent_id, &keys, recipient, capability)
+    }
+
+    // Takes the share of the folder at path back from user, along with the shares user passed on.
+    // The folder and everything below it get new keys, which are sealed again for the other recipients, and the tree
+    // is uploaded under them right away, so nothing written from now on can be read or signed with the keys user had.
+    pub fn revoke_share(&mut self, path: &str, user: &[u8]) -> Result<(), SafeStoreError> {
+        let (folder_id, _, _) = self.root.folder_key(path)?;
+        let recipient = self.server.get_user(user)?.id;
+        self.server.revoke_share(&self.token, folder_id, recipient)?;
+        self.upload()
+    }
+
+    // Takes a folder shared with this user with Admin back from user. Only the owner has the keys of the folder,
+    // the server holds back saves of it until the owner's client has rotated them, on its next upload.
+    pub fn revoke_recipient(&mut self, envelope: &KeyEnvelope, user: &[u8]) -> Result<(), SafeStoreError> {
+        let recipient = self.server.get_user(user)?.id;
+        self.server.revoke_share(&self.token, envelope.folder_id, recipient)
+    }
+
+    pub fn incoming_shares(&mut self) -> Result<Vec<Share>, SafeStoreError> {
+        self.server.list_incoming_shares(&self.token)
+    }
+
+    // The accepted folder is mounted as /shared/<owner>/<folder name>, with a number appended if that name is taken.
+    // The owner is the user whose tree holds the folder, as the server knows them, not what the folder itself claims.
+    // If the folder cannot be opened or mounted the share is left as it was. The link is part of this user's tree and
+    // is stored with it by the next close().
+    pub fn accept_share(&mut self, share_id: Uuid) -> Result<KeyEnvelope, SafeStoreError> {
+        let share = self.server.list_incoming_shares(&self.token)?.into_iter()
+            .find(|share| share.id == share_id)
+            .ok_or_else(|| SafeStoreError::NotFound(share_id.to_string()))?;
+        let owner = self.server.get_user_by_id(share.owner)?.name.clone();
+        let envelope = self.server.accept_share(&self.token, share_id)?;
+        let mounted = self.open_shared(&envelope)
+            .and_then(|folder| self.mount_share(&owner, &folder, share_id));
+        if let Err(err) = mounted {
+            if share.status == ShareStatus::Pending {
+                self.server.unaccept_share(&self.token, share_id)?;
+            }
+            return Err(err);
+        }
+        Ok(envelope)
+    }
+
+    // Mounted on a copy of the tree, which only replaces it once the link is in place
+    fn mount_share(&mut self, owner: &[u8], folder: &Folder, share_id: Uuid) -> Result<(), SafeStoreError> {
+        // Both names come from other users and each has to stay a single path segment
+        for name in [owner, folder.name.as_slice()] {
+            if name.is_empty() || name.contains(&b'/') || name == b"." || name == b".." {
+                return Err(SafeStoreError::InvalidPath(String::from_utf8_lossy(name).to_string()));
+            }
+        }
+        let mut root = self.root.duplicate();
+        let mount_point = root.create_dir_all(&format!("/shared/{}", String::from_utf8_lossy(owner)))?;
+        let mut name = folder.name.clone();
+        let mut copy = 1;
+        while mount_point.get(&String::from_utf8_lossy(&name)).is_ok() {
+            copy += 1;
+            name = [folder.name.as_slice(), format!(" ({})", copy).as_bytes()].concat();
+        }
+        mount_point.add_link(Link::new(name, owner.to_vec(), share_id));
+        self.root = root;
+        Ok(())
+    }
+
+    // Follows the link at path to the shared folder, as it is on the server now.
+    // Its current envelope is returned too, for save_shared.
+    pub fn open_link(&mut self, path: &str) -> Result<(KeyEnvelope, Folder), SafeStoreError> {
+        let share_id = match self.root.get(path)? {
+            Entry::Link(link) => link.share_id,
+            Entry::File(_) | Entry::Folder(_) => return Err(SafeStoreError::NotFound(path.to_string())),
+        };
+        let envelope = self.server.list_incoming_shares(&self.token)?.into_iter()
+            .find(|share| share.id == share_id)
+            .map(|share| share.envelope)
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))?;
+        let folder = self.open_shared(&envelope)?;
+        Ok((envelope, folder))
+    }
+
+    pub fn decline_share(&mut self, share_id: Uuid) -> Result<(), SafeStoreError> {
+        self.server.decline_share(&self.token, share_id)
+    }
+
+    // The folder is only decrypted if it was signed with its write key, so changes made without it are rejected.
+    // Only a writer gets the write keys of the shared folders inside it.
+    pub fn open_shared(&mut self, envelope: &KeyEnvelope) -> Result<Folder, SafeStoreError> {
+        let keys = self.open_envelope(envelope)?;
+        let enc_folder = self.server.get_folder(&self.token, envelope.folder_id)?;
+        enc_folder.verify_with(&keys.verify_key)?;
+        let wrap_key = keys.write_key.as_ref().map(|write_key| write_key.as_bytes());
+        let folder = enc_folder.symmetric_decrypt(keys.read_key.as_bytes(), wrap_key, Some(envelope.parent_id))?;
+        self.etags.insert(folder.id, enc_folder.etag()?);
+        Ok(folder)
+    }
+
+    // Uploads a folder returned by open_shared, encrypted again under the shared key and signed with the write key.
+    // Fails with Conflict if the folder changed on the server since it was last opened, it has to be opened again then.
+    // Shared folders inside it that this user cannot write are uploaded as stored, the server rejects any change to them.
+    pub fn save_shared(&mut self, envelope: &KeyEnvelope, folder: &Folder) -> Result<(), SafeStoreError> {
+        if folder.id != envelope.folder_id {
+            return Err(SafeStoreError::Malformed("folder does not match the share".to_string()));
+        }
+        let keys = self.open_envelope(envelope)?;
+        let write_key = keys.write_key.ok_or(SafeStoreError::PermissionDenied)?;
+        let mut enc_folder = folder.symmetric_encrypt(keys.read_key.as_bytes(), Some(write_key.as_bytes()), Some(envelope.parent_id), self.server.padding)?;
+        let writable: Vec<Uuid> = self.server.list_incoming_shares(&self.token)?.into_iter()
+            .filter(|share| share.status == ShareStatus::Accepted && share.capability >= Capability::Write)
+            .map(|share| share.envelope.folder_id)
+            .collect();
+        let nested_ids: Vec<Uuid> = enc_folder.shared_folder_ids().into_iter().filter(|id| !writable.contains(id)).collect();
+        if !nested_ids.is_empty() {
+            let stored = self.server.get_folder(&self.token, folder.id)?;
+            for nested_id in nested_ids {
+                if let (Some(nested), Some(stored_nested)) = (enc_folder.find_mut(nested_id), stored.find(nested_id)) {
+                    *nested = stored_nested.duplicate();
+                }
+            }
+        }
+        enc_folder.sign_with(&write_key)?;
+        let etag = *self.etags.get(&folder.id).ok_or(SafeStoreError::Conflict)?;
+        let new_etag = enc_folder.etag()?;
+        self.server.put_folder(&self.token, enc_folder, etag)?;
+        self.etags.insert(folder.id, new_etag);
+        Ok(())
+    }
+
+    // Fails with Conflict if the tree changed on the server since the session fetched it, e.g. because a recipient
+    // saved a folder shared by this user, rather than overwrite that change. The session comes back with the error.
+    pub fn close(mut self) -> Result<(), CloseError<'a>> {
+        let result = self.rotate_pending_keys()
+            .and_then(|()| self.root.symmetric_encrypt(self.master_key.as_bytes(), Some(self.master_key.as_bytes()), None, self.server.padding))
+            .and_then(|enc_root| self.server.logout(&self.token, enc_root, self.root_etag));
+        result.map_err(|error| CloseError { session: Box::new(self), error })
+    }
+
+    // Fetches the tree as stored, after an upload failed with Conflict. Shared folders that a recipient saved since
+    // the session fetched them are replaced with the stored version, changes made to them in this session are lost.
+    // Every other change in the session's tree is kept.
+    pub fn refresh(&mut self) -> Result<(), SafeStoreError> {
+        let enc_root = self.server.get_root_folder(&self.token)?;
+        let stored = enc_root.symmetric_decrypt(self.master_key.as_bytes(), Some(self.master_key.as_bytes()), None)?;
+        let etags = ClientSession::shared_etags(&enc_root)?;
+        for (folder_id, etag) in &etags {
+            if self.etags.get(folder_id) == Some(etag) {
+                continue;
+            }
+            if let (Some(folder), Some(stored_folder)) = (self.root.find_mut(*folder_id), stored.find(*folder_id)) {
+                *folder = stored_folder.duplicate();
+            }
+        }
+        self.root_etag = enc_root.etag()?;
+        self.etags.extend(etags);
+        Ok(())
+    }
+
+    fn send_share(&mut self, folder_id: Uuid, parent_id: Uuid, keys: &FolderKeys, recipient: &[u8], capability: Capability) -> Result<Uuid, SafeStoreError> {
+        let recipient = self.server.get_user(recipient)?;
+        let envelope = KeyEnvelope::seal(folder_id, parent_id, keys, &self.username, &self.keys.keypair.1, &recipient.public_key)?;
+        let recipient_id = recipient.id;
+        self.server.create_share(&self.token, recipient_id, envelope, capability)
+    }
+
+    // Parent id and keys to seal for a share of folder_id in root, the folder gets a write key the first time it is shared
+    fn folder_keys(root: &mut Folder, folder_id: Uuid, capability: Capability) -> Result<(Uuid, FolderKeys), SafeStoreError> {
+        let parent = root.parent_of_mut(folder_id).ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?;
+        let write_key = match parent.write_keys.entry(folder_id) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => entry.insert(WriteKey::generate()?),
+        };
+        let keys = FolderKeys {
+            read_key: parent.folder_keys.get(&folder_id).ok_or(SafeStoreError::MissingKey)?.duplicate(),
+            verify_key: cryptography::write_public_key(write_key)?,
+            write_key: (capability >= Capability::Write).then(|| write_key.duplicate()),
+        };
+        Ok((parent.id, keys))
+    }
+
+    // Uploads the tree without closing the session
+    fn upload(&mut self) -> Result<(), SafeStoreError> {
+        self.rotate_pending_keys()?;
+        let enc_root = self.root.symmetric_encrypt(self.master_key.as_bytes(), Some(self.master_key.as_bytes()), None, self.server.padding)?;
+        let etag = enc_root.etag()?;
+        let etags = ClientSession::shared_etags(&enc_root)?;
+        self.server.put_root_folder(&self.token, enc_root, self.root_etag)?;
+        self.root_etag = etag;
+        self.etags.extend(etags);
+        Ok(())
+    }
+
+    // Gives new keys to the folders of this user that had a share revoked, including by an Admin recipient, seals them
+    // again for every other share and uploads the tree under them. The keys are rotated on a copy of the tree, which only
+    // replaces it once the server took the new envelopes. Quarantined folders cannot be rotated and stay pending.
+    fn rotate_pending_keys(&mut self) -> Result<(), SafeStoreError> {
+        let pending = self.server.list_pending_rotations(&self.token)?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut root = self.root.duplicate();
+        let mut rotated = Vec::new();
+        let mut shares: BTreeMap<Uuid, Share> = BTreeMap::new();
+        for folder_id in pending {
+            if root.is_quarantined(folder_id) {
+                continue;
+            }
+            // Folders deleted since are left out of the tree, there is nothing to rotate
+            if root.find(folder_id).is_some() {
+                root.rotate_keys(folder_id)?;
+                for share in self.server.list_folder_shares(&self.token, folder_id)? {
+                    shares.insert(share.id, share);
+                }
+            }
+            rotated.push(folder_id);
+        }
+        if rotated.is_empty() {
+            return Ok(());
+        }
+
+        let mut envelopes = Vec::new();
+        for share in shares.into_values() {
+            let (parent_id, keys) = ClientSession::folder_keys(&mut root, share.envelope.folder_id, share.capability)?;
+            let recipient_pk = self.server.get_user_by_id(share.recipient)?.public_key;
+            let envelope = KeyEnvelope::seal(share.envelope.folder_id, parent_id, &keys, &self.username, &self.keys.keypair.1, &recipient_pk)?;
+            envelopes.push((share.id, envelope));
+        }
+        let enc_root = root.symmetric_encrypt(self.master_key.as_bytes(), Some(self.master_key.as_bytes()), None, self.server.padding)?;
+        let etag = enc_root.etag()?;
+        let etags = ClientSession::shared_etags(&enc_root)?;
+        self.server.rekey(&self.token, rotated, envelopes, enc_root, self.root_etag)?;
+        self.root = root;
+        self.root_etag = etag;
+        self.etags.extend(etags);
+        Ok(())
+    }
+
+    // Etags of the shared folders in an encrypted tree of this user, refresh compares them with the stored ones
+    fn shared_etags(enc_root: &Folder) -> Result<Vec<(Uuid, [u8; 32])>, SafeStoreError> {
+        enc_root.shared_folder_ids().into_iter()
+            .filter_map(|folder_id| enc_root.find(folder_id))
+            .map(|folder| Ok((folder.id, folder.etag()?)))
+            .collect()
+    }
+
+    // Envelopes can only be opened with the recipient's secret key, which only the recipient's client has
+    pub fn open_envelope(&self, envelope: &KeyEnvelope) -> Result<FolderKeys, SafeStoreError> {
+        let sender_pk = self.server.get_user(&envelope.sender)?.public_key;
+        envelope.open(&sender_pk, &self.keys.keypair.1)
+    }
+
+    fn replace_password(&mut self, old_password: &[u8], new_password: &[u8], kdf_params: &KdfParams) -> Result<(), SafeStoreError> {
+        let (login, proof, _) = ClientSession::prove_password(self.server, &self.client, &self.username, old_password)?;
+        let update = derive_credentials(self.user()?.id, new_password, &self.master_key, kdf_params)?;
+        let server_mac = self.server.change_password(&self.token, proof, update)?;
+        login.verify_server(&server_mac)
+    }
+
+    // Same password hashed with the parameters the server offered at login, the user's other sessions stay open
+    fn rehash_password(&mut self, password: &[u8], kdf_params: &KdfParams) -> Result<(), SafeStoreError> {
+        let (login, proof, _) = ClientSession::prove_password(self.server, &self.client, &self.username, password)?;
+        let update = derive_credentials(self.user()?.id, password, &self.master_key, kdf_params)?;
+        let server_mac = self.server.rehash_password(&self.token, proof, update)?;
+        login.verify_server(&server_mac)
+    }
+
+    // Runs the login protocol up to the client's proof, also returns the password hash the proof was derived from
+    fn prove_password(server: &mut Server, client: &[u8], username: &[u8], password: &[u8]) -> Result<(ClientLogin, LoginFinish, PasswordHash), SafeStoreError> {
+        let (password_salt, kdf_params) = server.get_password_salt(username.to_vec())?;
+        let (password_hash, _) = hash_password(password, Some(&password_salt), &kdf_params)?;
+
+        // In a deployment the server key would be pinned by the client, here the session talks to the server directly
+        let server_public_key = server.public_key();
+        let (mut login, start) = ClientLogin::start(username);
+        let challenge = server.login_start(client, username, start)?;
+        let finish = login.respond(&password_hash, &server_public_key, &challenge)?;
+        Ok((login, finish, password_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::registration::register;
+
+    fn server_with(names: &[&[u8]]) -> Server {
+        let kdf_params = KdfParams { m_cost: 8, t_cost: 1, p_cost: 1 };
+        let mut server = Server::new();
+        server.kdf_policy = kdf_params;
+        for name in names {
+            server.register(register(name, b"password", &kdf_params, server.padding).unwrap()).unwrap();
+        }
+        server
+    }
+
+    #[test]
+    fn owner_recovers_from_a_conflicting_close() {
+        let mut server = server_with(&[b"Alice", b"Bob"]);
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        let share_id = alice.share_folder("/home", b"Bob", Capability::Write).unwrap();
+        alice.close().unwrap();
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        bob.accept_share(share_id).unwrap();
+        bob.close().unwrap();
+
+        // Bob saves the shared folder while Alice's session is open
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().write_file("/notes", b"from Alice".to_vec()).unwrap();
+        let mut bob = ClientSession::open(alice.server, b"Bob", b"password").unwrap();
+        let (envelope, mut home) = bob.open_link("/shared/Alice/home").unwrap();
+        home.write_file("/report", b"from Bob".to_vec()).unwrap();
+        bob.save_shared(&envelope, &home).unwrap();
+        bob.close().unwrap();
+
+        let conflict = alice.close().unwrap_err();
+        assert!(matches!(conflict.error, SafeStoreError::Conflict), "{:?}", conflict);
+        let mut alice = conflict.session;
+        alice.refresh().unwrap();
+        alice.close().unwrap();
+
+        let alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        assert_eq!(alice.root().read_file("/notes").unwrap(), b"from Alice");
+        assert_eq!(alice.root().read_file("/home/report").unwrap(), b"from Bob");
+    }
+
+    #[test]
+    fn nested_shares_keep_read_and_write_apart() {
+        let mut server = server_with(&[b"Alice", b"Bob", b"Carol"]);
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home/reports").unwrap();
+        alice.root_mut().write_file("/home/reports/report", b"from Alice".to_vec()).unwrap();
+        let home_share = alice.share_folder("/home", b"Bob", Capability::Write).unwrap();
+        let reports_share = alice.share_folder("/home/reports", b"Bob", Capability::Read).unwrap();
+        let carol_share = alice.share_folder("/home", b"Carol", Capability::Read).unwrap();
+        let (reports_id, _, _) = alice.root().folder_key("/home/reports").unwrap();
+        alice.close().unwrap();
+
+        // Reading /home checks the reports with their verify key, their write key is only for writers of /home
+        let mut carol = ClientSession::open(&mut server, b"Carol", b"password").unwrap();
+        carol.accept_share(carol_share).unwrap();
+        let (_, home) = carol.open_link("/shared/Alice/home").unwrap();
+        assert!(home.write_keys.is_empty());
+        assert!(home.verify_keys.contains_key(&reports_id));
+        assert_eq!(home.read_file("/reports/report").unwrap(), b"from Alice");
+        carol.close().unwrap();
+
+        // Bob writes /home but only reads the reports: his save of /home keeps them as stored
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        bob.accept_share(home_share).unwrap();
+        bob.accept_share(reports_share).unwrap();
+        let (envelope, mut home) = bob.open_link("/shared/Alice/home").unwrap();
+        home.write_file("/notes", b"from Bob".to_vec()).unwrap();
+        home.write_file("/reports/report", b"from Bob".to_vec()).unwrap();
+        bob.save_shared(&envelope, &home).unwrap();
+        let (envelope, mut home) = bob.open_link("/shared/Alice/home").unwrap();
+        assert_eq!(home.read_file("/notes").unwrap(), b"from Bob");
+        assert_eq!(home.read_file("/reports/report").unwrap(), b"from Alice");
+
+        // And the server rejects a /home that changes them anyway
+        home.write_file("/reports/report", b"from Bob".to_vec()).unwrap();
+        let keys = bob.open_envelope(&envelope).unwrap();
+        let write_key = keys.write_key.unwrap();
+        let mut enc_home = home.symmetric_encrypt(keys.read_key.as_bytes(), Some(write_key.as_bytes()), Some(envelope.parent_id), bob.server.padding).unwrap();
+        enc_home.sign_with(&write_key).unwrap();
+        let etag = bob.etags[&home.id];
+        assert!(matches!(bob.server.put_folder(&bob.token, enc_home, etag), Err(SafeStoreError::PermissionDenied)));
+        bob.close().unwrap();
+    }
+
+    #[test]
+    fn saving_a_nested_share_keeps_its_parent_valid() {
+        let mut server = server_with(&[b"Alice", b"Bob", b"Carol"]);
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home/reports").unwrap();
+        let home_share = alice.share_folder("/home", b"Bob", Capability::Read).unwrap();
+        let reports_share = alice.share_folder("/home/reports", b"Carol", Capability::Write).unwrap();
+        let (reports_id, _, _) = alice.root().folder_key("/home/reports").unwrap();
+        alice.close().unwrap();
+
+        let mut carol = ClientSession::open(&mut server, b"Carol", b"password").unwrap();
+        carol.accept_share(reports_share).unwrap();
+        let (envelope, mut reports) = carol.open_link("/shared/Alice/reports").unwrap();
+        reports.write_file("/report", b"from Carol".to_vec()).unwrap();
+        carol.save_shared(&envelope, &reports).unwrap();
+        carol.close().unwrap();
+
+        // The signature of /home does not cover what is inside the reports
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        bob.accept_share(home_share).unwrap();
+        let (_, home) = bob.open_link("/shared/Alice/home").unwrap();
+        assert_eq!(home.read_file("/reports/report").unwrap(), b"from Carol");
+        bob.close().unwrap();
+
+        let alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        assert!(!alice.root().is_quarantined(reports_id));
+        assert_eq!(alice.root().read_file("/home/reports/report").unwrap(), b"from Carol");
+    }
+
+    #[test]
+    fn accepted_share_is_mounted_under_its_owner() {
+        let mut server = server_with(&[b"Alice", b"Bob"]);
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        alice.root_mut().create_dir_all("/escape").unwrap();
+        let home_share = alice.share_folder("/home", b"Bob", Capability::Read).unwrap();
+        let escape_share = alice.share_folder("/escape", b"Bob", Capability::Read).unwrap();
+        // Both are chosen by the sharer
+        alice.root_mut().folders[0].owner = b"../Bob".to_vec();
+        alice.root_mut().folders[1].name = b"../x".to_vec();
+        alice.close().unwrap();
+
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        bob.accept_share(home_share).unwrap();
+        assert!(matches!(bob.root().get("/shared/Alice/home"), Ok(Entry::Link(_))));
+
+        // Not mounted, and still pending
+        assert!(matches!(bob.accept_share(escape_share), Err(SafeStoreError::InvalidPath(_))));
+        let share = bob.incoming_shares().unwrap().into_iter().find(|share| share.id == escape_share).unwrap();
+        assert_eq!(share.status, ShareStatus::Pending);
+        match bob.root().get("/shared/Alice").unwrap() {
+            Entry::Folder(folder) => assert_eq!(folder.links.len(), 1),
+            _ => panic!("not a folder"),
+        }
+    }
+}
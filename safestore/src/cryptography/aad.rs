@@ -0,0 +1,63 @@
+use uuid::Uuid;
+
+// Bumped whenever the layout of the associated data changes, old ciphertexts then stop decrypting
+pub const AAD_VERSION: u8 = 1;
+
+const AAD_LABEL: &[u8] = b"safestore aad";
+
+// Which part of an object a ciphertext holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    Owner,
+    Data,
+    FileKey,
+    FolderKey,
+    MasterKey,
+    WriteKey,
+    UserKeys,
+}
+
+impl Field {
+    fn tag(self) -> u8 {
+        match self {
+            Field::Name => 1,
+            Field::Owner => 2,
+            Field::Data => 3,
+            Field::FileKey => 4,
+            Field::FolderKey => 5,
+            Field::MasterKey => 6,
+            Field::WriteKey => 7,
+            Field::UserKeys => 8,
+        }
+    }
+}
+
+// Context a symmetric ciphertext is bound to, authenticated but not encrypted by AES-GCM.
+// A ciphertext moved to another object, field or parent folder fails to decrypt.
+// parent_id is the nil UUID for objects without a parent (root folders, master keys).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssociatedData {
+    pub object_id: Uuid,
+    pub field: Field,
+    pub parent_id: Uuid,
+}
+
+impl AssociatedData {
+    pub fn new(object_id: Uuid, field: Field, parent_id: Option<Uuid>) -> AssociatedData {
+        AssociatedData {
+            object_id,
+            field,
+            parent_id: parent_id.unwrap_or(Uuid::nil()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = AAD_LABEL.to_vec();
+        bytes.push(AAD_VERSION);
+        bytes.extend_from_slice(self.object_id.as_bytes());
+        bytes.push(self.field.tag());
+        bytes.extend_from_slice(self.parent_id.as_bytes());
+        bytes
+    }
+}
@@ -1,80 +1,362 @@
-pub mod cryptography {
-    use aes_gcm::{
-        aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng}, Aes256Gcm, Error, Key
-    };
-    
-    use dryoc::classic::crypto_box::*;
-    use dryoc::constants::CRYPTO_BOX_MACBYTES;
-
-    use argon2::{
-    password_hash::{
-        PasswordHasher, SaltString
-        },
-        Argon2
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Key
+};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use dryoc::classic::crypto_box::*;
+use dryoc::classic::crypto_sign;
+use dryoc::constants::{CRYPTO_BOX_MACBYTES, CRYPTO_BOX_NONCEBYTES, CRYPTO_SIGN_BYTES};
+
+use argon2::{password_hash::PasswordHasher, Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::error::SafeStoreError;
+
+pub mod aad;
+pub mod padding;
+pub mod secret;
+pub mod stream;
+
+pub use argon2::password_hash::SaltString;
+pub use aad::{AssociatedData, Field};
+pub use padding::PaddingPolicy;
+pub use secret::{FileKey, MasterKey, PasswordHash, WriteKey};
+pub use stream::{decrypt_contents, decrypt_stream, encrypt_contents, encrypt_stream, DecryptReader, EncryptWriter};
+
+const KEY_LEN: usize = 32;
+
+// HKDF info strings, every key derived from the password hash gets its own
+pub const KEK_INFO: &[u8] = b"safestore v1 master key wrapping";
+pub const LOGIN_KEY_INFO: &[u8] = b"safestore v1 login key";
+
+pub fn get_random_key() -> Result<[u8; KEY_LEN], SafeStoreError> {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.try_fill_bytes(&mut key).map_err(|_| SafeStoreError::EncryptionFailed)?;
+    Ok(key)
+}
+
+// Symmetric ciphertexts start with the id of the AEAD that produced them, followed by the nonce.
+// PADDED_FLAG is set in the id when the plaintext was padded before encryption.
+// BOUND_FLAG is set when the header is part of the associated data, so that neither the id nor the flags can be changed.
+// Every new ciphertext sets it, ciphertexts without it are from before and are still opened with the bare associated data.
+const PADDED_FLAG: u8 = 0x80;
+const BOUND_FLAG: u8 = 0x40;
+// Header of contents sealed in chunks by stream::encrypt_contents instead of with a single AEAD call
+pub const STREAM_ID: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AeadAlgorithm {
+    // 96 bit random nonces, only safe for a limited number of encryptions under one key
+    Aes256Gcm,
+    // 192 bit random nonces, collisions are not a concern
+    #[default]
+    XChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    pub fn id(self) -> u8 {
+        match self {
+            AeadAlgorithm::Aes256Gcm => 1,
+            AeadAlgorithm::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<AeadAlgorithm, SafeStoreError> {
+        match id {
+            1 => Ok(AeadAlgorithm::Aes256Gcm),
+            2 => Ok(AeadAlgorithm::XChaCha20Poly1305),
+            _ => Err(SafeStoreError::Malformed(format!("unknown cipher id {}", id))),
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            AeadAlgorithm::Aes256Gcm => 12,
+            AeadAlgorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+pub fn symmetric_encrypt(key: &[u8], plaintext: &[u8], aad: &AssociatedData) -> Result<Vec<u8>, SafeStoreError> {
+    symmetric_encrypt_with(AeadAlgorithm::default(), PaddingPolicy::None, key, plaintext, aad)
+}
+
+// For plaintexts whose length should not be visible, keys have a fixed length and do not need it
+pub fn symmetric_encrypt_padded(key: &[u8], plaintext: &[u8], aad: &AssociatedData, padding: PaddingPolicy) -> Result<Vec<u8>, SafeStoreError> {
+    symmetric_encrypt_with(AeadAlgorithm::default(), padding, key, plaintext, aad)
+}
+
+pub fn symmetric_encrypt_with(algorithm: AeadAlgorithm, padding: PaddingPolicy, key: &[u8], plaintext: &[u8], aad: &AssociatedData) -> Result<Vec<u8>, SafeStoreError> {
+    if key.len() != KEY_LEN {
+        return Err(SafeStoreError::Malformed("symmetric key must be 32 bytes".to_string()));
+    }
+    let (padded, header) = match padding {
+        PaddingPolicy::None => (None, algorithm.id() | BOUND_FLAG),
+        _ => (Some(padding::pad(plaintext, padding)), algorithm.id() | BOUND_FLAG | PADDED_FLAG),
     };
+    let plaintext = padded.as_ref().map_or(plaintext, |padded| padded.as_slice());
+    let mut nonce = vec![0u8; algorithm.nonce_len()];
+    OsRng.try_fill_bytes(&mut nonce).map_err(|_| SafeStoreError::EncryptionFailed)?;
+
+    let aad = header_aad(header, aad);
+    let payload = Payload { msg: plaintext, aad: &aad };
+    let ciphered_data = match algorithm {
+        AeadAlgorithm::Aes256Gcm => Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .encrypt(aes_gcm::Nonce::from_slice(&nonce), payload),
+        AeadAlgorithm::XChaCha20Poly1305 => XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+            .encrypt(XNonce::from_slice(&nonce), payload),
+    }.map_err(|_| SafeStoreError::EncryptionFailed)?;
+
+    let mut encrypted_data: Vec<u8> = vec![header];
+    encrypted_data.extend_from_slice(&nonce);
+    encrypted_data.extend_from_slice(&ciphered_data);
+
+    Ok(encrypted_data)
+}
+
+// Fails unless aad is the one the data was encrypted with, the algorithm is read from the header
+pub fn symmetric_decrypt(key: &[u8], encrypted_data: &[u8], aad: &AssociatedData) -> Result<Vec<u8>, SafeStoreError> {
+    if key.len() != KEY_LEN {
+        return Err(SafeStoreError::Malformed("symmetric key must be 32 bytes".to_string()));
+    }
+    let (&header, rest) = encrypted_data.split_first()
+        .ok_or_else(|| SafeStoreError::Malformed("empty ciphertext".to_string()))?;
+    let algorithm = AeadAlgorithm::from_id(header & !(PADDED_FLAG | BOUND_FLAG))?;
+    if rest.len() < algorithm.nonce_len() {
+        return Err(SafeStoreError::Malformed("ciphertext shorter than its nonce".to_string()));
+    }
+    let (nonce, ciphered_data) = rest.split_at(algorithm.nonce_len());
+
+    let aad = header_aad(header, aad);
+    let payload = Payload { msg: ciphered_data, aad: &aad };
+    let plaintext = match algorithm {
+        AeadAlgorithm::Aes256Gcm => Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .decrypt(aes_gcm::Nonce::from_slice(nonce), payload),
+        AeadAlgorithm::XChaCha20Poly1305 => XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+            .decrypt(XNonce::from_slice(nonce), payload),
+    }.map_err(|_| SafeStoreError::DecryptionFailed)?;
 
-    pub fn get_random_key() -> Result<[u8; 32], Error> {
-        let mut key = [0u8; 32];
-        OsRng.fill_bytes(&mut key);
-        Ok(key)
+    if header & PADDED_FLAG != 0 {
+        padding::unpad(plaintext)
+    } else {
+        Ok(plaintext)
     }
-    
-    pub fn symmetric_encrypt(key: &[u8], plaintext: Vec<u8>) -> Vec<u8> {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        
-        let cipher = Aes256Gcm::new(key);
-    
-        let ciphered_data = cipher.encrypt(&nonce, plaintext.as_ref())
-            .expect("failed to encrypt");
-        
-        let mut encrypted_data: Vec<u8> = nonce.to_vec();
-        encrypted_data.extend_from_slice(&ciphered_data);
-    
-        encrypted_data
+}
+
+fn header_aad(header: u8, aad: &AssociatedData) -> Vec<u8> {
+    match header & BOUND_FLAG {
+        0 => aad.to_bytes(),
+        _ => [&[header][..], &aad.to_bytes()].concat(),
     }
-    
-    pub fn symmetric_decrypt(key: &[u8], encrypted_data: Vec<u8>) -> Vec<u8> {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-    
-        let (nonce_arr, ciphered_data) = encrypted_data.split_at(12);
-        let nonce = aes_gcm::Nonce::from_slice(nonce_arr);
-    
-        let cipher = Aes256Gcm::new(key);
-    
-        let plaintext = cipher.decrypt(nonce, ciphered_data)
-            .expect("failed to decrypt data");
-
-        plaintext.to_vec()
-    
+}
+
+// Sealed data starts with a version byte, asymmetric_decrypt picks the format by it rather than by trying each one
+const SEALED_NONCE_ID: u8 = 1;
+// Sealed by older versions with an all zero nonce and no nonce prefix, see add_legacy_sealed_header
+const SEALED_ZERO_NONCE_ID: u8 = 0;
+
+pub fn asymmetric_encrypt(sender_sk: &SecretKey, recipient_pk: &PublicKey, message: &[u8]) -> Result<Vec<u8>, SafeStoreError> {
+    // A fresh nonce for every message, prepended to the ciphertext like for symmetric_encrypt
+    let mut nonce = Nonce::default();
+    OsRng.try_fill_bytes(&mut nonce).map_err(|_| SafeStoreError::EncryptionFailed)?;
+    let mut ciphertext = vec![0u8; message.len() + CRYPTO_BOX_MACBYTES];
+    crypto_box_easy(&mut ciphertext, message, &nonce, recipient_pk, sender_sk)
+        .map_err(|_| SafeStoreError::EncryptionFailed)?;
+
+    let mut encrypted_data = vec![SEALED_NONCE_ID];
+    encrypted_data.extend_from_slice(&nonce);
+    encrypted_data.extend_from_slice(&ciphertext);
+    Ok(encrypted_data)
+}
+
+pub fn asymmetric_decrypt(sender_pk: &PublicKey, recipient_sk: &SecretKey, encrypted_data: &[u8]) -> Result<Vec<u8>, SafeStoreError> {
+    let (nonce, ciphertext) = match encrypted_data.split_first() {
+        Some((&SEALED_NONCE_ID, rest)) if rest.len() >= CRYPTO_BOX_NONCEBYTES => {
+            let (nonce, ciphertext) = rest.split_at(CRYPTO_BOX_NONCEBYTES);
+            (nonce.try_into().unwrap(), ciphertext)
+        }
+        Some((&SEALED_ZERO_NONCE_ID, ciphertext)) => (Nonce::default(), ciphertext),
+        Some((&SEALED_NONCE_ID, _)) => return Err(SafeStoreError::Malformed("sealed data shorter than its nonce".to_string())),
+        Some((id, _)) => return Err(SafeStoreError::Malformed(format!("unknown sealed data version {}", id))),
+        None => return Err(SafeStoreError::Malformed("empty sealed data".to_string())),
+    };
+    if ciphertext.len() < CRYPTO_BOX_MACBYTES {
+        return Err(SafeStoreError::Malformed("ciphertext shorter than its MAC".to_string()));
     }
+    let mut message = vec![0u8; ciphertext.len() - CRYPTO_BOX_MACBYTES];
+    crypto_box_open_easy(&mut message, ciphertext, &nonce, sender_pk, recipient_sk)
+        .map_err(|_| SafeStoreError::DecryptionFailed)?;
+    Ok(message)
+}
+
+// Data sealed before nonces were random has no version byte, prefixing it is enough for asymmetric_decrypt to open it.
+// Sealing it again with asymmetric_encrypt moves it to a random nonce.
+pub fn add_legacy_sealed_header(ciphertext: &[u8]) -> Vec<u8> {
+    let mut with_header = vec![SEALED_ZERO_NONCE_ID];
+    with_header.extend_from_slice(ciphertext);
+    with_header
+}
 
-    pub fn asymmetric_encrypt(sender_sk: SecretKey, recipient_pk: PublicKey, message: Vec<u8>) -> Vec<u8> {
-        // nonce is just default for now
-        let nonce = Nonce::default();
-        let mut ciphertext = vec![0u8; message.len() + CRYPTO_BOX_MACBYTES];
-        crypto_box_easy(&mut ciphertext, &message, &nonce, &recipient_pk, &sender_sk)
-            .expect("encrypt failed");
-        ciphertext
+// Detached Ed25519 signatures under a key pair derived from a WriteKey.
+// The public half is what readers check signatures with, it is safe to hand out.
+pub fn write_public_key(write_key: &WriteKey) -> Result<[u8; 32], SafeStoreError> {
+    let seed: &[u8; 32] = write_key.as_bytes().try_into()
+        .map_err(|_| SafeStoreError::Malformed("write key must be 32 bytes".to_string()))?;
+    Ok(crypto_sign::crypto_sign_seed_keypair(seed).0)
+}
+
+pub fn sign_detached(write_key: &WriteKey, message: &[u8]) -> Result<Vec<u8>, SafeStoreError> {
+    let seed: &[u8; 32] = write_key.as_bytes().try_into()
+        .map_err(|_| SafeStoreError::Malformed("write key must be 32 bytes".to_string()))?;
+    let secret_key = Zeroizing::new(crypto_sign::crypto_sign_seed_keypair(seed).1);
+    let mut signature = [0u8; CRYPTO_SIGN_BYTES];
+    crypto_sign::crypto_sign_detached(&mut signature, message, &secret_key)
+        .map_err(|_| SafeStoreError::SignatureInvalid)?;
+    Ok(signature.to_vec())
+}
+
+pub fn verify_detached(public_key: &[u8; 32], message: &[u8], signature: &[u8]) -> Result<(), SafeStoreError> {
+    let signature: &[u8; CRYPTO_SIGN_BYTES] = signature.try_into().map_err(|_| SafeStoreError::SignatureInvalid)?;
+    crypto_sign::crypto_sign_verify_detached(signature, message, public_key)
+        .map_err(|_| SafeStoreError::SignatureInvalid)
+}
+
+// Argon2id cost parameters, stored with each password salt so that the hash can be recomputed after the policy changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    // memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
     }
+}
 
-    pub fn asymmetric_decrypt(sender_pk: PublicKey, recipient_sk: SecretKey, ciphertext: Vec<u8>) -> Vec<u8> {
-        // nonce is just default for now
-        let nonce = Nonce::default();
-        let mut message = vec![0u8; ciphertext.len() - CRYPTO_BOX_MACBYTES];
-        crypto_box_open_easy(&mut message, &ciphertext, &nonce, &sender_pk, &recipient_sk)
-            .expect("decrypt failed");
-        message
+impl KdfParams {
+    // True if any of the costs is below the one of other
+    pub fn is_weaker_than(&self, other: &KdfParams) -> bool {
+        self.m_cost < other.m_cost || self.t_cost < other.t_cost || self.p_cost < other.p_cost
     }
 
-    pub fn hash_password(password: Vec<u8>, given_salt: Option<&SaltString>) -> (Vec<u8>, SaltString) {
-        let mut salt = SaltString::generate(&mut OsRng);
-        if !given_salt.is_none() {
-            salt = given_salt.unwrap().clone();
+    // The highest of each cost, so that an upgrade never lowers one of them
+    pub fn strongest(&self, other: &KdfParams) -> KdfParams {
+        KdfParams {
+            m_cost: self.m_cost.max(other.m_cost),
+            t_cost: self.t_cost.max(other.t_cost),
+            p_cost: self.p_cost.max(other.p_cost),
         }
-        let argon2 = Argon2::default();
-        let password_hash = argon2.hash_password(&password, &salt);
-        let hash = password_hash.unwrap().hash.unwrap().as_bytes().to_vec();
-        return (hash, salt.clone());
     }
-}
\ No newline at end of file
+}
+
+pub fn hash_password(password: &[u8], given_salt: Option<&SaltString>, params: &KdfParams) -> Result<(PasswordHash, SaltString), SafeStoreError> {
+    let salt = match given_salt {
+        Some(salt) => salt.clone(),
+        None => SaltString::generate(&mut OsRng),
+    };
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|err| SafeStoreError::Malformed(format!("invalid password hashing parameters: {}", err)))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    let password_hash = argon2.hash_password(password, &salt)
+        .map_err(|err| SafeStoreError::Malformed(format!("password hashing failed: {}", err)))?;
+    let hash = password_hash.hash
+        .ok_or_else(|| SafeStoreError::Malformed("password hashing produced no output".to_string()))?;
+    Ok((PasswordHash::new(hash.as_bytes().to_vec()), salt))
+}
+
+// Expands a secret (e.g. the Argon2 password hash) into a 32 byte key for the purpose named by info
+pub fn derive_key(secret: &[u8], info: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>, SafeStoreError> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(info, key.as_mut())
+        .map_err(|err| SafeStoreError::Malformed(format!("key derivation failed: {}", err)))?;
+    Ok(key)
+}
+
+// The master key is random and generated once, only the key encryption key depends on the password.
+// Changing the password rewraps the master key and leaves everything encrypted under it untouched.
+// The wrapped key is bound to the id of the user it belongs to.
+pub fn wrap_master_key(password_hash: &PasswordHash, master_key: &MasterKey, user_id: Uuid) -> Result<Vec<u8>, SafeStoreError> {
+    let aad = AssociatedData::new(user_id, Field::MasterKey, None);
+    symmetric_encrypt(derive_key(password_hash.as_bytes(), KEK_INFO)?.as_ref(), master_key.as_bytes(), &aad)
+}
+
+pub fn unwrap_master_key(password_hash: &PasswordHash, enc_master_key: &[u8], user_id: Uuid) -> Result<MasterKey, SafeStoreError> {
+    let aad = AssociatedData::new(user_id, Field::MasterKey, None);
+    symmetric_decrypt(derive_key(password_hash.as_bytes(), KEK_INFO)?.as_ref(), enc_master_key, &aad).map(MasterKey::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_cannot_be_changed() {
+        let key = get_random_key().unwrap();
+        let aad = AssociatedData::new(Uuid::new_v4(), Field::Data, None);
+        let encrypted = symmetric_encrypt_padded(&key, b"hello", &aad, PaddingPolicy::Padme).unwrap();
+        assert_eq!(symmetric_decrypt(&key, &encrypted, &aad).unwrap(), b"hello");
+
+        for flag in [PADDED_FLAG, BOUND_FLAG] {
+            let mut cleared = encrypted.clone();
+            cleared[0] &= !flag;
+            assert!(matches!(symmetric_decrypt(&key, &cleared, &aad), Err(SafeStoreError::DecryptionFailed)));
+        }
+    }
+
+    #[test]
+    fn aes_gcm_ciphertexts_still_decrypt() {
+        let key = get_random_key().unwrap();
+        let aad = AssociatedData::new(Uuid::new_v4(), Field::Name, None);
+        let nonce = [7u8; 12];
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        // Sealed directly with AES-256-GCM, once with the header bound and once with the bare associated data
+        for header in [AeadAlgorithm::Aes256Gcm.id() | BOUND_FLAG, AeadAlgorithm::Aes256Gcm.id()] {
+            let payload = Payload { msg: b"hello", aad: &header_aad(header, &aad) };
+            let mut encrypted = vec![header];
+            encrypted.extend_from_slice(&nonce);
+            encrypted.extend_from_slice(&cipher.encrypt(aes_gcm::Nonce::from_slice(&nonce), payload).unwrap());
+            assert_eq!(symmetric_decrypt(&key, &encrypted, &aad).unwrap(), b"hello");
+            let other = AssociatedData::new(Uuid::new_v4(), Field::Name, None);
+            assert!(matches!(symmetric_decrypt(&key, &encrypted, &other), Err(SafeStoreError::DecryptionFailed)));
+        }
+
+        let encrypted = symmetric_encrypt_with(AeadAlgorithm::Aes256Gcm, PaddingPolicy::Padme, &key, b"hello", &aad).unwrap();
+        assert_eq!(encrypted[0], AeadAlgorithm::Aes256Gcm.id() | BOUND_FLAG | PADDED_FLAG);
+        assert_eq!(symmetric_decrypt(&key, &encrypted, &aad).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn sealed_format_is_chosen_by_its_version() {
+        let (sender_pk, sender_sk) = crypto_box_keypair();
+        let (recipient_pk, recipient_sk) = crypto_box_keypair();
+        let sealed = asymmetric_encrypt(&sender_sk, &recipient_pk, b"hello").unwrap();
+        assert_eq!(sealed[0], SEALED_NONCE_ID);
+        assert_eq!(asymmetric_decrypt(&sender_pk, &recipient_sk, &sealed).unwrap(), b"hello");
+
+        // Sealed under the zero nonce by older versions
+        let mut legacy = vec![0u8; 5 + CRYPTO_BOX_MACBYTES];
+        crypto_box_easy(&mut legacy, b"hello", &Nonce::default(), &recipient_pk, &sender_sk).unwrap();
+        assert!(matches!(asymmetric_decrypt(&sender_pk, &recipient_sk, &legacy), Err(SafeStoreError::Malformed(_))));
+        let legacy = add_legacy_sealed_header(&legacy);
+        assert_eq!(asymmetric_decrypt(&sender_pk, &recipient_sk, &legacy).unwrap(), b"hello");
+
+        // A damaged ciphertext fails, it is not retried as the other format
+        let mut damaged = sealed.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(matches!(asymmetric_decrypt(&sender_pk, &recipient_sk, &damaged), Err(SafeStoreError::DecryptionFailed)));
+        let mut relabeled = sealed;
+        relabeled[0] = SEALED_ZERO_NONCE_ID;
+        assert!(matches!(asymmetric_decrypt(&sender_pk, &recipient_sk, &relabeled), Err(SafeStoreError::DecryptionFailed)));
+    }
+}
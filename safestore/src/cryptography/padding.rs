@@ -0,0 +1,148 @@
+use std::io::{self, Read, Write};
+
+use zeroize::Zeroizing;
+
+use crate::error::SafeStoreError;
+
+// How far plaintexts are padded before encryption, so that ciphertext lengths only leak a bucket.
+// Padded plaintexts are framed as data || 0x80 || 0x00.., the true length is recovered from the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingPolicy {
+    // Exact lengths are visible
+    None,
+    // Up to 100% overhead, only the order of magnitude of the length leaks
+    PowerOfTwo,
+    // Padmé: at most 12% overhead, leaks O(log log L) bits of the length
+    #[default]
+    Padme,
+}
+
+impl PaddingPolicy {
+    pub fn id(self) -> u32 {
+        match self {
+            PaddingPolicy::None => 0,
+            PaddingPolicy::PowerOfTwo => 1,
+            PaddingPolicy::Padme => 2,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Result<PaddingPolicy, SafeStoreError> {
+        match id {
+            0 => Ok(PaddingPolicy::None),
+            1 => Ok(PaddingPolicy::PowerOfTwo),
+            2 => Ok(PaddingPolicy::Padme),
+            _ => Err(SafeStoreError::Malformed(format!("unknown padding policy {}", id))),
+        }
+    }
+
+    pub fn padded_len(self, len: usize) -> usize {
+        match self {
+            PaddingPolicy::None => len,
+            PaddingPolicy::PowerOfTwo => len.next_power_of_two(),
+            PaddingPolicy::Padme => padme(len),
+        }
+    }
+}
+
+// Rounds len up so that only its exponent and the top bits of its mantissa are kept
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let exponent = len.ilog2();
+    let significant_bits = exponent.ilog2() + 1;
+    let mask = (1usize << (exponent - significant_bits)) - 1;
+    (len + mask) & !mask
+}
+
+pub fn pad(plaintext: &[u8], policy: PaddingPolicy) -> Zeroizing<Vec<u8>> {
+    let mut padded = Zeroizing::new(Vec::with_capacity(policy.padded_len(plaintext.len() + 1)));
+    padded.extend_from_slice(plaintext);
+    padded.push(0x80);
+    padded.resize(policy.padded_len(plaintext.len() + 1), 0);
+    padded
+}
+
+// Frames a plaintext of len bytes that was already written out, for contents that are not held in memory
+pub fn write_padding<W: Write>(writer: &mut W, len: u64, policy: PaddingPolicy) -> io::Result<()> {
+    let padding_len = policy.padded_len(len as usize + 1) - len as usize;
+    writer.write_all(&[0x80])?;
+    io::copy(&mut io::repeat(0).take(padding_len as u64 - 1), writer)?;
+    Ok(())
+}
+
+pub fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, SafeStoreError> {
+    let marker = padded.iter().rposition(|&byte| byte != 0)
+        .filter(|&position| padded[position] == 0x80)
+        .ok_or_else(|| SafeStoreError::Malformed("invalid padding".to_string()))?;
+    padded.truncate(marker);
+    Ok(padded)
+}
+
+// Strips the frame from a padded plaintext as it is written through, without buffering the padding:
+// a 0x80 and the zeros after it are only passed on once something other than zeros follows them
+pub struct UnpadWriter<W: Write> {
+    inner: W,
+    // Zeros seen after a held back 0x80
+    held_zeros: Option<u64>,
+    written: u64,
+}
+
+impl<W: Write> UnpadWriter<W> {
+    pub fn new(inner: W) -> UnpadWriter<W> {
+        UnpadWriter { inner, held_zeros: None, written: 0 }
+    }
+
+    // Fails unless what was written ends with a complete frame, returns the length of the plaintext
+    pub fn finish(mut self) -> Result<u64, SafeStoreError> {
+        if self.held_zeros.is_none() {
+            return Err(SafeStoreError::Malformed("invalid padding".to_string()));
+        }
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        if let Some(zeros) = self.held_zeros.take() {
+            self.inner.write_all(&[0x80])?;
+            io::copy(&mut io::repeat(0).take(zeros), &mut self.inner)?;
+            self.written += zeros + 1;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for UnpadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (position, &byte) in buf.iter().enumerate() {
+            match &mut self.held_zeros {
+                Some(zeros) if byte == 0 => {
+                    *zeros += 1;
+                    continue;
+                }
+                // Not the end of the plaintext after all
+                Some(_) => {
+                    self.release()?;
+                    start = position;
+                }
+                None => {}
+            }
+            if byte == 0x80 {
+                self.inner.write_all(&buf[start..position])?;
+                self.written += (position - start) as u64;
+                self.held_zeros = Some(0);
+                start = position + 1;
+            }
+        }
+        if self.held_zeros.is_none() {
+            self.inner.write_all(&buf[start..])?;
+            self.written += (buf.len() - start) as u64;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+use zeroize::Zeroize;
+
+use super::get_random_key;
+use crate::error::SafeStoreError;
+
+// Owned secret bytes that are wiped when dropped and never printed.
+// They are deliberately not Clone, copies have to be made explicitly with duplicate().
+macro_rules! secret_bytes {
+    ($name:ident) => {
+        pub struct $name(Vec<u8>);
+
+        impl $name {
+            pub fn new(bytes: Vec<u8>) -> $name {
+                $name(bytes)
+            }
+
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.0
+            }
+
+            pub fn duplicate(&self) -> $name {
+                $name(self.0.clone())
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.0.zeroize();
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}(..)", stringify!($name))
+            }
+        }
+    };
+}
+
+// Random key generated once per user, everything the user owns is encrypted under it
+secret_bytes!(MasterKey);
+// Key of a single file or folder. In an encrypted tree the same slot holds the key wrapped by the parent's key.
+secret_bytes!(FileKey);
+// Ed25519 seed a shared folder is signed with, only recipients allowed to write are given it
+secret_bytes!(WriteKey);
+// Argon2 output of the password, from which the login key and the master key wrapping key are derived
+secret_bytes!(PasswordHash);
+
+impl MasterKey {
+    pub fn generate() -> Result<MasterKey, SafeStoreError> {
+        let mut key = get_random_key()?;
+        let master_key = MasterKey(key.to_vec());
+        key.zeroize();
+        Ok(master_key)
+    }
+}
+
+impl FileKey {
+    pub fn generate() -> Result<FileKey, SafeStoreError> {
+        let mut key = get_random_key()?;
+        let file_key = FileKey(key.to_vec());
+        key.zeroize();
+        Ok(file_key)
+    }
+}
+
+impl WriteKey {
+    pub fn generate() -> Result<WriteKey, SafeStoreError> {
+        let mut seed = get_random_key()?;
+        let write_key = WriteKey(seed.to_vec());
+        seed.zeroize();
+        Ok(write_key)
+    }
+}
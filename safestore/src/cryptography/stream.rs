@@ -0,0 +1,331 @@
+use std::io::{self, Read, Write};
+
+use aes_gcm::aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+use super::padding::{self, PaddingPolicy, UnpadWriter};
+use super::{AssociatedData, KEY_LEN, STREAM_ID};
+use crate::error::SafeStoreError;
+
+// Chunked encryption in the style of the STREAM construction, for contents too large to be held in memory.
+// Every chunk is sealed with XChaCha20-Poly1305 under the nonce prefix || chunk counter || last chunk flag,
+// so dropping, reordering or cutting off chunks makes decryption fail, and a flipped bit only spoils its own chunk.
+//
+// Layout: STREAM_VERSION (u8), chunk size (u32), random nonce prefix, then the sealed chunks.
+// Every chunk but the last one holds exactly chunk size bytes of plaintext, the last one may be empty.
+
+pub const STREAM_VERSION: u8 = 1;
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+// Bounds what a corrupted header can make the reader allocate
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const PREFIX_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 1 + 4 + PREFIX_LEN;
+
+struct ChunkCipher {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    // The caller's associated data followed by the stream header
+    aad: Vec<u8>,
+    counter: u32,
+}
+
+impl ChunkCipher {
+    fn new(key: &[u8], header: &[u8; HEADER_LEN], aad: &AssociatedData) -> Result<ChunkCipher, SafeStoreError> {
+        if key.len() != KEY_LEN {
+            return Err(SafeStoreError::Malformed("symmetric key must be 32 bytes".to_string()));
+        }
+        let mut prefix = [0u8; PREFIX_LEN];
+        prefix.copy_from_slice(&header[HEADER_LEN - PREFIX_LEN..]);
+        let mut chunk_aad = aad.to_bytes();
+        chunk_aad.extend_from_slice(header);
+        Ok(ChunkCipher {
+            cipher: XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key)),
+            prefix,
+            aad: chunk_aad,
+            counter: 0,
+        })
+    }
+
+    // Also moves on to the next chunk
+    fn next_nonce(&mut self, last: bool) -> Result<XNonce, SafeStoreError> {
+        let mut nonce = XNonce::default();
+        nonce[..PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[PREFIX_LEN..PREFIX_LEN + 4].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[PREFIX_LEN + 4] = last as u8;
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| SafeStoreError::Malformed("too many chunks in stream".to_string()))?;
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, SafeStoreError> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.encrypt(&nonce, Payload { msg: chunk, aad: &self.aad })
+            .map_err(|_| SafeStoreError::EncryptionFailed)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, SafeStoreError> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.decrypt(&nonce, Payload { msg: chunk, aad: &self.aad })
+            .map_err(|_| SafeStoreError::DecryptionFailed)
+    }
+}
+
+// Encrypts everything written to it into inner. finish() has to be called at the end,
+// otherwise the last chunk is never written and the stream reads as truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    chunks: ChunkCipher,
+    chunk_size: usize,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: &[u8], aad: &AssociatedData) -> Result<EncryptWriter<W>, SafeStoreError> {
+        EncryptWriter::with_chunk_size(inner, key, aad, DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(mut inner: W, key: &[u8], aad: &AssociatedData, chunk_size: usize) -> Result<EncryptWriter<W>, SafeStoreError> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(SafeStoreError::Malformed(format!("chunk size must be between 1 and {} bytes", MAX_CHUNK_SIZE)));
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = STREAM_VERSION;
+        header[1..5].copy_from_slice(&(chunk_size as u32).to_le_bytes());
+        OsRng.try_fill_bytes(&mut header[5..]).map_err(|_| SafeStoreError::EncryptionFailed)?;
+
+        let chunks = ChunkCipher::new(key, &header, aad)?;
+        inner.write_all(&header)?;
+        Ok(EncryptWriter {
+            inner,
+            chunks,
+            chunk_size,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size)),
+        })
+    }
+
+    // Seals the last chunk and hands back the inner writer
+    pub fn finish(mut self) -> Result<W, SafeStoreError> {
+        let sealed = self.chunks.seal(&self.buffer, true)?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // A full buffer is only sealed once more data arrives, until then it could still be the last chunk
+        if self.buffer.len() == self.chunk_size {
+            let sealed = self.chunks.seal(&self.buffer, false).map_err(into_io)?;
+            self.inner.write_all(&sealed)?;
+            self.buffer.clear();
+        }
+        let written = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Decrypts a stream written by EncryptWriter.
+// The plaintext of a chunk is handed out as soon as that chunk authenticates, so a stream
+// that was cut off is only reported once the reader gets to its end.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    chunks: ChunkCipher,
+    chunk_size: usize,
+    // Sealed bytes read ahead of the current chunk
+    sealed: Vec<u8>,
+    plaintext: Zeroizing<Vec<u8>>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &[u8], aad: &AssociatedData) -> Result<DecryptReader<R>, SafeStoreError> {
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => SafeStoreError::Malformed("truncated stream header".to_string()),
+            _ => SafeStoreError::Io(err),
+        })?;
+        if header[0] != STREAM_VERSION {
+            return Err(SafeStoreError::Malformed(format!("unsupported stream version {}", header[0])));
+        }
+        let chunk_size = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(SafeStoreError::Malformed(format!("invalid chunk size {}", chunk_size)));
+        }
+        Ok(DecryptReader {
+            inner,
+            chunks: ChunkCipher::new(key, &header, aad)?,
+            chunk_size,
+            sealed: Vec::new(),
+            plaintext: Zeroizing::new(Vec::new()),
+            position: 0,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> Result<(), SafeStoreError> {
+        let sealed_len = self.chunk_size + TAG_LEN;
+        // Reading one byte past a full chunk tells whether it is the last one
+        while self.sealed.len() <= sealed_len {
+            let mut buf = vec![0u8; sealed_len + 1 - self.sealed.len()];
+            let read = match self.inner.read(&mut buf) {
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(SafeStoreError::Io(err)),
+            };
+            if read == 0 {
+                break;
+            }
+            self.sealed.extend_from_slice(&buf[..read]);
+        }
+        let last = self.sealed.len() <= sealed_len;
+        let chunk: Vec<u8> = self.sealed.drain(..sealed_len.min(self.sealed.len())).collect();
+        self.plaintext = Zeroizing::new(self.chunks.open(&chunk, last)?);
+        self.position = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(into_io)?;
+        }
+        let read = buf.len().min(self.plaintext.len() - self.position);
+        buf[..read].copy_from_slice(&self.plaintext[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// Encrypts everything reader yields into writer, returns the number of plaintext bytes
+pub fn encrypt_stream<R: Read, W: Write>(key: &[u8], aad: &AssociatedData, mut reader: R, writer: W) -> Result<u64, SafeStoreError> {
+    let mut encryptor = EncryptWriter::new(writer, key, aad)?;
+    let length = io::copy(&mut reader, &mut encryptor).map_err(from_io)?;
+    encryptor.finish()?;
+    Ok(length)
+}
+
+// Whatever was written to writer before an error is unauthenticated and has to be discarded
+pub fn decrypt_stream<R: Read, W: Write>(key: &[u8], aad: &AssociatedData, reader: R, mut writer: W) -> Result<u64, SafeStoreError> {
+    let mut decryptor = DecryptReader::new(reader, key, aad)?;
+    io::copy(&mut decryptor, &mut writer).map_err(from_io)
+}
+
+// Contents as they are stored in a tree: a STREAM_ID byte, then the stream of the padded plaintext.
+// The padding is always framed, even with PaddingPolicy::None, so the header carries no padded flag.
+pub fn encrypt_contents<R: Read, W: Write>(key: &[u8], aad: &AssociatedData, padding: PaddingPolicy, mut reader: R, mut writer: W) -> Result<u64, SafeStoreError> {
+    writer.write_all(&[STREAM_ID])?;
+    let mut encryptor = EncryptWriter::new(writer, key, aad)?;
+    let length = io::copy(&mut reader, &mut encryptor).map_err(from_io)?;
+    padding::write_padding(&mut encryptor, length, padding).map_err(from_io)?;
+    encryptor.finish()?;
+    Ok(length)
+}
+
+// Returns the length of the plaintext, what was written to writer before an error has to be discarded like with decrypt_stream
+pub fn decrypt_contents<R: Read, W: Write>(key: &[u8], aad: &AssociatedData, mut reader: R, writer: W) -> Result<u64, SafeStoreError> {
+    let mut header = [0u8; 1];
+    reader.read_exact(&mut header).map_err(|_| SafeStoreError::Malformed("empty ciphertext".to_string()))?;
+    if header[0] != STREAM_ID {
+        return Err(SafeStoreError::Malformed(format!("not a chunked ciphertext, cipher id {}", header[0])));
+    }
+    let mut unpadder = UnpadWriter::new(writer);
+    decrypt_stream(key, aad, reader, &mut unpadder)?;
+    unpadder.finish()
+}
+
+fn into_io(err: SafeStoreError) -> io::Error {
+    match err {
+        SafeStoreError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+fn from_io(err: io::Error) -> SafeStoreError {
+    if !err.get_ref().is_some_and(|inner| inner.is::<SafeStoreError>()) {
+        return SafeStoreError::Io(err);
+    }
+    match err.into_inner().map(|inner| inner.downcast::<SafeStoreError>()) {
+        Some(Ok(inner)) => *inner,
+        _ => SafeStoreError::Malformed("stream error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::{get_random_key, Field};
+    use uuid::Uuid;
+
+    const CHUNK_SIZE: usize = 4;
+    const SEALED_LEN: usize = CHUNK_SIZE + TAG_LEN;
+
+    // Header followed by three sealed chunks holding 4, 4 and 2 bytes
+    fn encrypt(key: &[u8], aad: &AssociatedData) -> Vec<u8> {
+        let mut encryptor = EncryptWriter::with_chunk_size(Vec::new(), key, aad, CHUNK_SIZE).unwrap();
+        encryptor.write_all(b"0123456789").unwrap();
+        let encrypted = encryptor.finish().unwrap();
+        assert_eq!(encrypted.len(), HEADER_LEN + 2 * SEALED_LEN + 2 + TAG_LEN);
+        encrypted
+    }
+
+    fn decrypt(key: &[u8], aad: &AssociatedData, encrypted: &[u8]) -> Result<Vec<u8>, SafeStoreError> {
+        let mut plaintext = Vec::new();
+        decrypt_stream(key, aad, encrypted, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn dropped_last_chunk_is_detected() {
+        let key = get_random_key().unwrap();
+        let aad = AssociatedData::new(Uuid::new_v4(), Field::Data, None);
+        let mut encrypted = encrypt(&key, &aad);
+        assert_eq!(decrypt(&key, &aad, &encrypted).unwrap(), b"0123456789");
+
+        encrypted.truncate(HEADER_LEN + 2 * SEALED_LEN);
+        assert!(matches!(decrypt(&key, &aad, &encrypted), Err(SafeStoreError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn swapped_chunks_are_detected() {
+        let key = get_random_key().unwrap();
+        let aad = AssociatedData::new(Uuid::new_v4(), Field::Data, None);
+        let mut encrypted = encrypt(&key, &aad);
+        let (first, second) = encrypted[HEADER_LEN..HEADER_LEN + 2 * SEALED_LEN].split_at_mut(SEALED_LEN);
+        first.swap_with_slice(second);
+        assert!(matches!(decrypt(&key, &aad, &encrypted), Err(SafeStoreError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn cleared_final_flag_is_detected() {
+        let key = get_random_key().unwrap();
+        let aad = AssociatedData::new(Uuid::new_v4(), Field::Data, None);
+        let encrypted = encrypt(&key, &aad);
+        let header: [u8; HEADER_LEN] = encrypted[..HEADER_LEN].try_into().unwrap();
+
+        // The same chunks sealed again, the last one without the final flag
+        let mut chunks = ChunkCipher::new(&key, &header, &aad).unwrap();
+        let mut resealed = header.to_vec();
+        for chunk in [&b"0123"[..], b"4567", b"89"] {
+            resealed.extend_from_slice(&chunks.seal(chunk, false).unwrap());
+        }
+        assert_eq!(resealed.len(), encrypted.len());
+        assert!(matches!(decrypt(&key, &aad, &resealed), Err(SafeStoreError::DecryptionFailed)));
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SafeStoreError {
+    // The credentials provided by the client do not match the ones stored on the server
+    AuthenticationFailed,
+    UnknownUser,
+    UsernameTaken,
+    // Too many failed logins for this username or client, retry_after is in seconds
+    RateLimited { retry_after: u64 },
+    // The session token is unknown, revoked or timed out
+    InvalidSession,
+    // A password change that does not replace the salt, login key and encrypted master key together
+    PartialCredentialUpdate(String),
+    EncryptionFailed,
+    // Wrong key or tampered ciphertext, AEAD does not let us tell the two apart
+    DecryptionFailed,
+    // The caller can see the shared folder but its capability does not allow this
+    PermissionDenied,
+    // A file or folder has no matching entry in its parent's key list
+    MissingKey,
+    SignatureInvalid,
+    // Path based operations on a decrypted folder tree
+    NotFound(String),
+    AlreadyExists(String),
+    InvalidPath(String),
+    // The stored folder changed since the client fetched it, the upload would overwrite someone else's changes
+    Conflict,
+    // Data that does not have the expected structure (truncated ciphertext, bad salt, corrupted store, ...)
+    Malformed(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for SafeStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafeStoreError::AuthenticationFailed => write!(f, "authentication failed"),
+            SafeStoreError::UnknownUser => write!(f, "unknown user"),
+            SafeStoreError::UsernameTaken => write!(f, "username already taken"),
+            SafeStoreError::InvalidSession => write!(f, "invalid or expired session"),
+            SafeStoreError::PartialCredentialUpdate(missing) => write!(f, "partial credential update, missing {}", missing),
+            SafeStoreError::RateLimited { retry_after } => write!(f, "too many failed login attempts, retry in {}s", retry_after),
+            SafeStoreError::EncryptionFailed => write!(f, "encryption failed"),
+            SafeStoreError::DecryptionFailed => write!(f, "decryption failed"),
+            SafeStoreError::PermissionDenied => write!(f, "permission denied"),
+            SafeStoreError::MissingKey => write!(f, "missing key"),
+            SafeStoreError::SignatureInvalid => write!(f, "invalid signature"),
+            SafeStoreError::NotFound(path) => write!(f, "no such file or folder: {}", path),
+            SafeStoreError::AlreadyExists(path) => write!(f, "file or folder already exists: {}", path),
+            SafeStoreError::InvalidPath(path) => write!(f, "invalid path: {}", path),
+            SafeStoreError::Conflict => write!(f, "folder changed since it was fetched"),
+            SafeStoreError::Malformed(reason) => write!(f, "malformed data: {}", reason),
+            SafeStoreError::Io(err) => write!(f, "i/o error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SafeStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SafeStoreError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SafeStoreError {
+    fn from(err: std::io::Error) -> Self {
+        SafeStoreError::Io(err)
+    }
+}
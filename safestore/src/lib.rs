@@ -0,0 +1,19 @@
+//! SafeStore: an end-to-end encrypted file store.
+//!
+//! Clients encrypt their folder trees before handing them to the `Server`,
+//! which only ever stores ciphertexts, salts and public login keys.
+
+pub mod authentication;
+pub mod client;
+pub mod cryptography;
+pub mod error;
+pub mod sharing;
+pub mod storage;
+
+pub use authentication::user::User;
+pub use client::registration::{register, Registration};
+pub use client::session::ClientSession;
+pub use error::SafeStoreError;
+pub use storage::file::File;
+pub use storage::folder::Folder;
+pub use storage::server::Server;
@@ -0,0 +1,85 @@
+use dryoc::classic::crypto_box::{PublicKey, SecretKey};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::cryptography::{self, FileKey, WriteKey};
+use crate::error::SafeStoreError;
+use crate::storage::encoding::{Reader, Writer};
+
+// The key of a shared folder sealed to one recipient with crypto_box, so that only they can open it
+// and they can check who sealed it. The server only has the users' public keys, their secret keys are
+// wrapped under their master keys, so envelopes are sealed and opened on the clients, see ClientSession::open_envelope.
+// The folder itself stays where it is on the server, encrypted under that same key, so the owner and the recipient
+// read and write the same ciphertext.
+// folder_id and parent_id locate the folder and are part of its associated data: moving the folder
+// elsewhere changes them and the owner has to share it again.
+// Besides the folder key, the envelope holds the public key the folder's signature is checked with
+// and, for recipients allowed to write, the write key that signs it.
+#[derive(Debug, Clone)]
+pub struct KeyEnvelope {
+    pub folder_id: Uuid,
+    pub parent_id: Uuid,
+    // Username of whoever sealed the envelope, their public key is needed to open it
+    pub sender: Vec<u8>,
+    pub sealed_key: Vec<u8>,
+}
+
+// What an envelope holds once opened
+#[derive(Debug)]
+pub struct FolderKeys {
+    pub read_key: FileKey,
+    pub verify_key: [u8; 32],
+    pub write_key: Option<WriteKey>,
+}
+
+impl KeyEnvelope {
+    pub fn seal(folder_id: Uuid, parent_id: Uuid, keys: &FolderKeys, sender: &[u8], sender_sk: &SecretKey, recipient_pk: &PublicKey) -> Result<KeyEnvelope, SafeStoreError> {
+        // The ids are sealed along with the keys so that the envelope cannot be pointed at another folder
+        let mut plaintext = Zeroizing::new(Vec::new());
+        plaintext.extend_from_slice(folder_id.as_bytes());
+        plaintext.extend_from_slice(parent_id.as_bytes());
+        plaintext.extend_from_slice(keys.read_key.as_bytes());
+        plaintext.extend_from_slice(&keys.verify_key);
+        if let Some(write_key) = &keys.write_key {
+            plaintext.extend_from_slice(write_key.as_bytes());
+        }
+        Ok(KeyEnvelope {
+            folder_id,
+            parent_id,
+            sender: sender.to_vec(),
+            sealed_key: cryptography::asymmetric_encrypt(sender_sk, recipient_pk, &plaintext)?,
+        })
+    }
+
+    pub fn open(&self, sender_pk: &PublicKey, recipient_sk: &SecretKey) -> Result<FolderKeys, SafeStoreError> {
+        let plaintext = Zeroizing::new(cryptography::asymmetric_decrypt(sender_pk, recipient_sk, &self.sealed_key)?);
+        if plaintext.len() < 32 || plaintext[..16] != *self.folder_id.as_bytes() || plaintext[16..32] != *self.parent_id.as_bytes() {
+            return Err(SafeStoreError::DecryptionFailed);
+        }
+        // Folder key and verify key, then the write key if there is one
+        let write_key = match plaintext.len() - 32 {
+            64 => None,
+            96 => Some(WriteKey::new(plaintext[96..].to_vec())),
+            _ => return Err(SafeStoreError::Malformed("unexpected envelope length".to_string())),
+        };
+        let mut verify_key = [0u8; 32];
+        verify_key.copy_from_slice(&plaintext[64..96]);
+        Ok(FolderKeys { read_key: FileKey::new(plaintext[32..64].to_vec()), verify_key, write_key })
+    }
+
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_raw(self.folder_id.as_bytes());
+        writer.write_raw(self.parent_id.as_bytes());
+        writer.write_bytes(&self.sender);
+        writer.write_bytes(&self.sealed_key);
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<KeyEnvelope, SafeStoreError> {
+        Ok(KeyEnvelope {
+            folder_id: reader.read_uuid()?,
+            parent_id: reader.read_uuid()?,
+            sender: reader.read_bytes()?,
+            sealed_key: reader.read_bytes()?,
+        })
+    }
+}
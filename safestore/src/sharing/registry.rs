@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use super::envelope::KeyEnvelope;
+use crate::error::SafeStoreError;
+use crate::storage::encoding::{Reader, Writer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareStatus {
+    // Waiting in the recipient's inbox
+    Pending,
+    // The recipient can use the folder as far as its capability allows
+    Accepted,
+}
+
+// What the recipient of a share may do with the folder, every level includes the ones before it.
+// The server enforces it, and only envelopes of Write and above carry the write key the folder is signed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    Read,
+    Write,
+    // Share the folder on, with at most the same capability
+    Reshare,
+    // Revoke anyone's share of the folder
+    Admin,
+}
+
+impl Capability {
+    pub fn id(self) -> u32 {
+        match self {
+            Capability::Read => 0,
+            Capability::Write => 1,
+            Capability::Reshare => 2,
+            Capability::Admin => 3,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Result<Capability, SafeStoreError> {
+        match id {
+            0 => Ok(Capability::Read),
+            1 => Ok(Capability::Write),
+            2 => Ok(Capability::Reshare),
+            3 => Ok(Capability::Admin),
+            _ => Err(SafeStoreError::Malformed(format!("unknown capability {}", id))),
+        }
+    }
+}
+
+// A folder key offered by one user to another, users are identified by id.
+// sender is the owner of the folder, or a recipient allowed to reshare it.
+#[derive(Debug, Clone)]
+pub struct Share {
+    pub id: Uuid,
+    // The user whose tree holds the folder, the server only looks for the folder there
+    pub owner: Uuid,
+    pub sender: Uuid,
+    pub recipient: Uuid,
+    pub envelope: KeyEnvelope,
+    pub status: ShareStatus,
+    pub capability: Capability,
+}
+
+// Every share known to the server, declined shares are dropped.
+// A revoked folder stays in pending_rotations, with its owner, until the owner's client has given it new keys.
+#[derive(Debug, Default)]
+pub struct ShareRegistry {
+    shares: BTreeMap<Uuid, Share>,
+    pending_rotations: BTreeMap<Uuid, Uuid>,
+}
+
+impl ShareRegistry {
+    pub fn insert(&mut self, owner: Uuid, sender: Uuid, recipient: Uuid, envelope: KeyEnvelope, capability: Capability) -> Uuid {
+        let id = Uuid::new_v4();
+        self.shares.insert(id, Share { id, owner, sender, recipient, envelope, status: ShareStatus::Pending, capability });
+        id
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Share> {
+        self.shares.values()
+    }
+
+    // Owner recorded for the shares of folder_id, None if the folder was never shared
+    pub fn owner(&self, folder_id: Uuid) -> Option<Uuid> {
+        self.shares.values().find(|share| share.envelope.folder_id == folder_id).map(|share| share.owner)
+            .or_else(|| self.pending_rotations.get(&folder_id).copied())
+    }
+
+    pub fn incoming(&self, recipient: Uuid) -> impl Iterator<Item = &Share> {
+        self.shares.values().filter(move |share| share.recipient == recipient)
+    }
+
+    pub fn outgoing(&self, sender: Uuid) -> impl Iterator<Item = &Share> {
+        self.shares.values().filter(move |share| share.sender == sender)
+    }
+
+    // Removes every share of folder_id to recipient, whoever sent it, returns how many there were
+    pub fn revoke(&mut self, folder_id: Uuid, recipient: Uuid) -> usize {
+        let before = self.shares.len();
+        self.shares.retain(|_, share| !(share.recipient == recipient && share.envelope.folder_id == folder_id));
+        before - self.shares.len()
+    }
+
+    // Shares of folder_id passed on by recipients who no longer have a share of it themselves, and in turn
+    // the shares those recipients passed on. Returns how many were removed.
+    pub fn revoke_orphaned_reshares(&mut self, folder_id: Uuid, owner: Uuid) -> usize {
+        let before = self.shares.len();
+        loop {
+            let senders: Vec<Uuid> = self.shares.values()
+                .filter(|share| share.envelope.folder_id == folder_id)
+                .map(|share| share.recipient)
+                .collect();
+            let orphaned: Vec<Uuid> = self.shares.values()
+                .filter(|share| share.envelope.folder_id == folder_id && share.sender != owner && !senders.contains(&share.sender))
+                .map(|share| share.id)
+                .collect();
+            if orphaned.is_empty() {
+                return before - self.shares.len();
+            }
+            for share_id in orphaned {
+                self.shares.remove(&share_id);
+            }
+        }
+    }
+
+    pub fn mark_for_rotation(&mut self, folder_id: Uuid, owner: Uuid) {
+        self.pending_rotations.insert(folder_id, owner);
+    }
+
+    // Folders of owner waiting for new keys
+    pub fn pending_rotations(&self, owner: Uuid) -> Vec<Uuid> {
+        self.pending_rotations.iter()
+            .filter(|(_, folder_owner)| **folder_owner == owner)
+            .map(|(folder_id, _)| *folder_id)
+            .collect()
+    }
+
+    pub fn clear_rotation(&mut self, folder_id: Uuid) {
+        self.pending_rotations.remove(&folder_id);
+    }
+
+    pub fn get_by_id(&self, share_id: Uuid) -> Result<&Share, SafeStoreError> {
+        self.shares.get(&share_id).ok_or_else(|| SafeStoreError::NotFound(share_id.to_string()))
+    }
+
+    pub fn get_by_id_mut(&mut self, share_id: Uuid) -> Result<&mut Share, SafeStoreError> {
+        self.shares.get_mut(&share_id).ok_or_else(|| SafeStoreError::NotFound(share_id.to_string()))
+    }
+
+    // Only the recipient can act on a share, anyone else gets NotFound as if it did not exist
+    pub fn get_mut(&mut self, share_id: Uuid, recipient: Uuid) -> Result<&mut Share, SafeStoreError> {
+        self.shares.get_mut(&share_id)
+            .filter(|share| share.recipient == recipient)
+            .ok_or_else(|| SafeStoreError::NotFound(share_id.to_string()))
+    }
+
+    pub fn remove(&mut self, share_id: Uuid, recipient: Uuid) -> Result<Share, SafeStoreError> {
+        self.get_mut(share_id, recipient)?;
+        self.shares.remove(&share_id).ok_or_else(|| SafeStoreError::NotFound(share_id.to_string()))
+    }
+
+    // The highest capability user_id accepted on folder_id, None without an accepted share
+    pub fn capability(&self, user_id: Uuid, folder_id: Uuid) -> Option<Capability> {
+        self.shares.values()
+            .filter(|share| share.recipient == user_id && share.status == ShareStatus::Accepted && share.envelope.folder_id == folder_id)
+            .map(|share| share.capability)
+            .max()
+    }
+
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_u64(self.shares.len() as u64);
+        for share in self.shares.values() {
+            writer.write_raw(share.id.as_bytes());
+            writer.write_raw(share.owner.as_bytes());
+            writer.write_raw(share.sender.as_bytes());
+            writer.write_raw(share.recipient.as_bytes());
+            writer.write_u32(match share.status {
+                ShareStatus::Pending => 0,
+                ShareStatus::Accepted => 1,
+            });
+            writer.write_u32(share.capability.id());
+            share.envelope.encode(writer);
+        }
+        writer.write_u64(self.pending_rotations.len() as u64);
+        for (folder_id, owner) in &self.pending_rotations {
+            writer.write_raw(folder_id.as_bytes());
+            writer.write_raw(owner.as_bytes());
+        }
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<ShareRegistry, SafeStoreError> {
+        let mut registry = ShareRegistry::default();
+        for _ in 0..reader.read_u64()? {
+            let id = reader.read_uuid()?;
+            let owner = reader.read_uuid()?;
+            let sender = reader.read_uuid()?;
+            let recipient = reader.read_uuid()?;
+            let status = match reader.read_u32()? {
+                0 => ShareStatus::Pending,
+                1 => ShareStatus::Accepted,
+                status => return Err(SafeStoreError::Malformed(format!("unknown share status {}", status))),
+            };
+            let capability = Capability::from_id(reader.read_u32()?)?;
+            let envelope = KeyEnvelope::decode(reader)?;
+            registry.shares.insert(id, Share { id, owner, sender, recipient, envelope, status, capability });
+        }
+        for _ in 0..reader.read_u64()? {
+            registry.pending_rotations.insert(reader.read_uuid()?, reader.read_uuid()?);
+        }
+        Ok(registry)
+    }
+}
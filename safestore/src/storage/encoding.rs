@@ -0,0 +1,93 @@
+use uuid::Uuid;
+
+use crate::error::SafeStoreError;
+
+// Minimal length-prefixed binary encoding used to persist the server state.
+// Every integer is little endian, every byte string is prefixed by its length as a u64.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Writer::new()
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SafeStoreError> {
+        let raw = self.read_raw(4)?;
+        Ok(u32::from_le_bytes(raw.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SafeStoreError> {
+        let raw = self.read_raw(8)?;
+        Ok(u64::from_le_bytes(raw.try_into().unwrap()))
+    }
+
+    pub fn read_raw(&mut self, len: usize) -> Result<&'a [u8], SafeStoreError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(SafeStoreError::Malformed("truncated data".to_string()));
+        }
+        let raw = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(raw)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, SafeStoreError> {
+        let len = usize::try_from(self.read_u64()?)
+            .map_err(|_| SafeStoreError::Malformed("length out of range".to_string()))?;
+        Ok(self.read_raw(len)?.to_vec())
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], SafeStoreError> {
+        let raw = self.read_bytes()?;
+        raw.try_into()
+            .map_err(|_| SafeStoreError::Malformed("unexpected length".to_string()))
+    }
+
+    pub fn read_uuid(&mut self) -> Result<Uuid, SafeStoreError> {
+        let raw = self.read_raw(16)?;
+        Ok(Uuid::from_bytes(raw.try_into().unwrap()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
@@ -1,23 +1,55 @@
-use dryoc::classic::crypto_box::PublicKey;
-use dryoc::{classic::crypto_box::SecretKey, sign::SignedMessage};
+use std::fmt;
+use std::io::{Read, Write};
+
+use dryoc::sign::SignedMessage;
 use dryoc::types::StackByteArray;
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
-use crate::{authentication::user, cryptography::cryptography};
+use crate::{authentication::user, cryptography};
+use crate::cryptography::{AssociatedData, Field, PaddingPolicy};
+use crate::error::SafeStoreError;
+use super::encoding::{Reader, Writer};
 
-#[derive(Debug)]
-#[derive(Clone)]
+// Not Clone, so that copies of the plaintext are only made on purpose, see duplicate
 pub struct File {
+    // Random and stable across encryption, used to find the key of the file in its parent folder
+    pub id: Uuid,
     pub name: Vec<u8>,
     pub owner: Vec<u8>,
-    pub data: Vec<u8>,
+    // Wiped when the file is dropped, it holds the plaintext in a decrypted tree
+    pub data: Zeroizing<Vec<u8>>,
     pub signature: Vec<u8>,
 }
 
+// The content is left out so that a decrypted file cannot end up in logs
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("File")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("owner", &self.owner)
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
 impl File {
-    pub fn factory(owner: &Vec<u8>) -> File {
+    pub fn factory(owner: &[u8]) -> File {
         let name = File::random_name();
         let data = File::random_content();
-        File::new(name.into_bytes(), owner.clone(), data.into_bytes())
+        File::new(name.into_bytes(), owner.to_vec(), data.into_bytes())
+    }
+
+    pub fn duplicate(&self) -> File {
+        File {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner.clone(),
+            data: Zeroizing::new(self.data.to_vec()),
+            signature: self.signature.clone(),
+        }
     }
 
     pub fn set_owner(&mut self, owner: Vec<u8>) {
@@ -29,46 +61,42 @@ impl File {
         format!("{}├── File: name: {}, content: {}", indent, String::from_utf8_lossy(&self.name), String::from_utf8_lossy(&self.data))
     }
 
-    pub fn symmetric_encrypt(&self, key: Vec<u8>) -> File {
+    // Every field is bound to the file id, the field kind and the folder holding the file
+    pub fn symmetric_encrypt(&self, key: &[u8], parent: Uuid, padding: PaddingPolicy) -> Result<File, SafeStoreError> {
         // We need to encrypt: name, data, owner
-        let encrypted_name = cryptography::symmetric_encrypt(&key, self.name.clone());
-        let encrypted_data = cryptography::symmetric_encrypt(&key, self.data.clone());
-        let encrypted_owner = cryptography::symmetric_encrypt(&key, self.owner.clone());
+        let encrypted_name = cryptography::symmetric_encrypt_padded(key, &self.name, &self.aad(Field::Name, parent), padding)?;
+        let mut encrypted_data = Vec::new();
+        self.encrypt_data_from(key, parent, padding, self.data.as_slice(), &mut encrypted_data)?;
+        let encrypted_owner = cryptography::symmetric_encrypt_padded(key, &self.owner, &self.aad(Field::Owner, parent), padding)?;
 
-        let encrypted_file = File::new(encrypted_name, encrypted_owner, encrypted_data);
-        encrypted_file
+        Ok(self.with_fields(encrypted_name, encrypted_owner, encrypted_data))
     }
 
-    pub fn symmetric_decrypt(&self, key: Vec<u8>) -> File {
+    pub fn symmetric_decrypt(&self, key: &[u8], parent: Uuid) -> Result<File, SafeStoreError> {
         // We need to decrypt: name, data, owner
-        let decrypted_name = cryptography::symmetric_decrypt(&key, self.name.clone());
-        let decrypted_data = cryptography::symmetric_decrypt(&key, self.data.clone());
-        let decrypted_owner = cryptography::symmetric_decrypt(&key, self.owner.clone());
+        let decrypted_name = cryptography::symmetric_decrypt(key, &self.name, &self.aad(Field::Name, parent))?;
+        let mut decrypted_data = Zeroizing::new(Vec::new());
+        self.decrypt_data_to(key, parent, &mut *decrypted_data)?;
+        let decrypted_owner = cryptography::symmetric_decrypt(key, &self.owner, &self.aad(Field::Owner, parent))?;
 
-        let decrypted_file = File::new(decrypted_name, decrypted_owner, decrypted_data);
-        decrypted_file
+        Ok(self.with_fields(decrypted_name, decrypted_owner, std::mem::take(&mut *decrypted_data)))
     }
 
-    pub fn asymmetric_encrypt(&self, receiver: (PublicKey, SecretKey), sender: (PublicKey, SecretKey)) -> File {
-        // We need to encrypt: name, data, owner
-        let encrypted_name = cryptography::asymmetric_encrypt(sender.1, receiver.0, self.name.clone());
-        let encrypted_data = cryptography::asymmetric_encrypt(sender.1, receiver.0, self.data.clone());
-        let encrypted_owner = cryptography::asymmetric_encrypt(sender.1, receiver.0, self.owner.clone());
-        
-        let encrypted_file = File::new(encrypted_name, encrypted_owner, encrypted_data);
-        encrypted_file
+    // The contents are sealed in chunks, so they can also be encrypted from and decrypted to disk without holding them in memory.
+    // encrypt_data_from writes what symmetric_encrypt stores as the data of the encrypted file.
+    pub fn encrypt_data_from<R: Read, W: Write>(&self, key: &[u8], parent: Uuid, padding: PaddingPolicy, reader: R, writer: W) -> Result<u64, SafeStoreError> {
+        cryptography::encrypt_contents(key, &self.aad(Field::Data, parent), padding, reader, writer)
     }
 
-    pub fn asymmetric_decrypt(&self, receiver: (PublicKey, SecretKey), sender: (PublicKey, SecretKey)) -> File {
-        // We need to decrypt: name, data, owner
-        let decrypted_name = cryptography::asymmetric_decrypt(sender.0, receiver.1, self.name.clone());
-        let decrypted_data = cryptography::asymmetric_decrypt(sender.0, receiver.1, self.data.clone());
-        let decrypted_owner = cryptography::asymmetric_decrypt(sender.0, receiver.1, self.owner.clone());
+    // On an encrypted file, decrypts its data chunk by chunk into writer
+    pub fn decrypt_data_to<W: Write>(&self, key: &[u8], parent: Uuid, writer: W) -> Result<u64, SafeStoreError> {
+        cryptography::decrypt_contents(key, &self.aad(Field::Data, parent), self.data.as_slice(), writer)
+    }
 
-        let decrypted_file = File::new(decrypted_name, decrypted_owner, decrypted_data);
-        decrypted_file
+    fn aad(&self, field: Field, parent: Uuid) -> AssociatedData {
+        AssociatedData::new(self.id, field, Some(parent))
     }
-    
+
     fn random_content() -> String {
         let index = rand::random::<usize>() % File::FILE_CONTENTS.len();
         File::FILE_CONTENTS[index].to_string()
@@ -78,31 +106,47 @@ impl File {
         let index = rand::random::<usize>() % File::FILE_NAMES.len();
         File::FILE_NAMES[index].to_string()
     }
-    
+
 
     pub fn new(name: Vec<u8>, owner: Vec<u8>, data: Vec<u8>) -> File {
         File {
+            id: Uuid::new_v4(),
+            name,
+            owner,
+            data: Zeroizing::new(data),
+            signature: Vec::new(),
+        }
+    }
+
+    // Same file (same id) with encrypted or decrypted fields
+    fn with_fields(&self, name: Vec<u8>, owner: Vec<u8>, data: Vec<u8>) -> File {
+        File {
+            id: self.id,
             name,
             owner,
-            data,
+            data: Zeroizing::new(data),
             signature: Vec::new(),
         }
     }
 
-    pub fn sign(&mut self, user: &user::User) {
-        let signature = user.signing_keypair.sign_with_defaults(self.data.clone()).expect("Error signing");
+    pub fn sign(&mut self, keys: &user::UserKeys) -> Result<(), SafeStoreError> {
+        let signature = keys.signing_keypair.sign_with_defaults(self.data.to_vec())
+            .map_err(|_| SafeStoreError::SignatureInvalid)?;
         self.signature = signature.to_bytes();
+        Ok(())
     }
 
-    pub fn verify(&self, user: &user::User) {
-        let signature: SignedMessage<StackByteArray<64>, Vec<u8>> = SignedMessage::from_bytes(&self.signature).expect("Error parsing signature");
+    pub fn verify(&self, user: &user::User) -> Result<(), SafeStoreError> {
+        let signature: SignedMessage<StackByteArray<64>, Vec<u8>> = SignedMessage::from_bytes(&self.signature)
+            .map_err(|_| SafeStoreError::SignatureInvalid)?;
         signature
-            .verify(&user.signing_keypair.public_key)
-            .expect("Error verifying");
+            .verify(&StackByteArray::from(user.signing_public_key))
+            .map_err(|_| SafeStoreError::SignatureInvalid)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        bytes.extend_from_slice(self.id.as_bytes());
         bytes.extend_from_slice(&self.name);
         bytes.extend_from_slice(&self.owner);
         bytes.extend_from_slice(&self.data);
@@ -110,7 +154,66 @@ impl File {
         bytes
     }
 
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_raw(self.id.as_bytes());
+        writer.write_bytes(&self.name);
+        writer.write_bytes(&self.owner);
+        writer.write_bytes(&self.data);
+        writer.write_bytes(&self.signature);
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<File, SafeStoreError> {
+        Ok(File {
+            id: reader.read_uuid()?,
+            name: reader.read_bytes()?,
+            owner: reader.read_bytes()?,
+            data: Zeroizing::new(reader.read_bytes()?),
+            signature: reader.read_bytes()?,
+        })
+    }
+
     const FILE_CONTENTS: [&'static str; 3] = ["Hello, World!", "This is a file.", "This is a file too."];
     const FILE_NAMES: [&'static str; 3] = ["myfile", "anotherfile", "athirdfile"];
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::FileKey;
+
+    #[test]
+    fn swapped_fields_do_not_decrypt() {
+        let key = FileKey::generate().unwrap();
+        let parent = Uuid::new_v4();
+        let file = File::new(b"name".to_vec(), b"owner".to_vec(), b"data".to_vec());
+        let mut enc_file = file.symmetric_encrypt(key.as_bytes(), parent, PaddingPolicy::default()).unwrap();
+        assert_eq!(enc_file.symmetric_decrypt(key.as_bytes(), parent).unwrap().data.as_slice(), b"data");
+
+        std::mem::swap(&mut enc_file.name, &mut enc_file.owner);
+        assert!(matches!(enc_file.symmetric_decrypt(key.as_bytes(), parent), Err(SafeStoreError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn data_is_chunked_and_streams_back() {
+        let key = FileKey::generate().unwrap();
+        let parent = Uuid::new_v4();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).chain([0x80, 0, 0]).collect();
+        let file = File::new(b"name".to_vec(), b"owner".to_vec(), data.clone());
+        let enc_file = file.symmetric_encrypt(key.as_bytes(), parent, PaddingPolicy::Padme).unwrap();
+        assert_eq!(enc_file.data[0], cryptography::STREAM_ID);
+
+        let mut streamed = Vec::new();
+        assert_eq!(enc_file.decrypt_data_to(key.as_bytes(), parent, &mut streamed).unwrap(), data.len() as u64);
+        assert_eq!(streamed, data);
+        assert_eq!(enc_file.symmetric_decrypt(key.as_bytes(), parent).unwrap().data.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn file_does_not_decrypt_in_another_folder() {
+        let key = FileKey::generate().unwrap();
+        let file = File::new(b"name".to_vec(), b"owner".to_vec(), b"data".to_vec());
+        let enc_file = file.symmetric_encrypt(key.as_bytes(), Uuid::new_v4(), PaddingPolicy::default()).unwrap();
+        assert!(matches!(enc_file.symmetric_decrypt(key.as_bytes(), Uuid::new_v4()), Err(SafeStoreError::DecryptionFailed)));
+    }
+}
@@ -1,47 +1,426 @@
 use super::file::File;
-use crate::cryptography::cryptography;
+use super::link::Link;
+use super::encoding::{Reader, Writer};
+use crate::cryptography::{self, AssociatedData, Field, FileKey, PaddingPolicy, WriteKey};
 use crate::authentication::user;
+use crate::error::SafeStoreError;
 
+use dryoc::classic::crypto_generichash::crypto_generichash;
 use dryoc::sign::SignedMessage;
-use dryoc::classic::crypto_box::*;
 use dryoc::types::*;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+// An entry of a decrypted folder tree, as returned by the path based lookups
+pub enum Entry<'a> {
+    File(&'a File),
+    Folder(&'a Folder),
+    Link(&'a Link),
+}
+
+pub enum EntryMut<'a> {
+    File(&'a mut File),
+    Folder(&'a mut Folder),
+    Link(&'a mut Link),
+}
 
+// An entry taken out of its parent together with the key that encrypts it, links have none
+enum Detached {
+    File(File, FileKey),
+    Folder(Folder, FileKey),
+    Link(Link),
+}
+
+// Not Clone, so that the keys it holds are not copied by accident, see duplicate()
 #[derive(Debug)]
-#[derive(Clone)]
 pub struct Folder {
+    // Random and stable across encryption, used to find the key of the folder in its parent
+    pub id: Uuid,
     pub name: Vec<u8>,
     pub owner: Vec<u8>,
     pub files: Vec<File>,
     pub folders: Vec<Folder>,
+    // Shared folders mounted here, see Link
+    pub links: Vec<Link>,
     pub signature: Vec<u8>,
 
-    // Key value pairs of file name and key used to encrypt the file
-    pub file_keys: Vec<(Vec<u8>, Vec<u8>)>,
-    pub folder_keys: Vec<(Vec<u8>, Vec<u8>)>,
+    // Keys used to encrypt each file and sub folder, indexed by their id
+    pub file_keys: BTreeMap<Uuid, FileKey>,
+    pub folder_keys: BTreeMap<Uuid, FileKey>,
+    // Write keys of the sub folders that were shared, an encrypted sub folder with a write key is signed with it.
+    // They are wrapped under the write key of the nearest shared folder above, so only its writers get them.
+    pub write_keys: BTreeMap<Uuid, WriteKey>,
+    // Public halves of the write keys, in clear, for those who can read a shared sub folder but not unwrap its write key
+    pub verify_keys: BTreeMap<Uuid, [u8; 32]>,
+    // Shared sub folders of a decrypted tree that did not pass their signature check or did not decrypt, still encrypted.
+    // They are stored back unchanged with the tree, so whatever a recipient broke stays in that folder and out of the rest of the tree.
+    pub quarantined: Vec<Folder>,
 }
 
 impl Folder {
 
     pub fn new(name: Vec<u8>, owner: Vec<u8>) -> Folder {
         Folder {
+            id: Uuid::new_v4(),
             name,
             owner,
             files: Vec::new(),
             folders: Vec::new(),
+            links: Vec::new(),
             signature: Vec::new(),
-            file_keys: Vec::new(),
-            folder_keys: Vec::new(),
+            file_keys: BTreeMap::new(),
+            folder_keys: BTreeMap::new(),
+            write_keys: BTreeMap::new(),
+            verify_keys: BTreeMap::new(),
+            quarantined: Vec::new(),
+        }
+    }
+
+    // Explicit deep copy, keys included
+    pub fn duplicate(&self) -> Folder {
+        Folder {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner.clone(),
+            files: self.files.iter().map(File::duplicate).collect(),
+            folders: self.folders.iter().map(Folder::duplicate).collect(),
+            links: self.links.clone(),
+            signature: self.signature.clone(),
+            file_keys: self.file_keys.iter().map(|(id, key)| (*id, key.duplicate())).collect(),
+            folder_keys: self.folder_keys.iter().map(|(id, key)| (*id, key.duplicate())).collect(),
+            write_keys: self.write_keys.iter().map(|(id, key)| (*id, key.duplicate())).collect(),
+            verify_keys: self.verify_keys.clone(),
+            quarantined: self.quarantined.iter().map(Folder::duplicate).collect(),
         }
     }
 
-    pub fn add_file(&mut self, file: File, key: Vec<u8>) {
-        self.file_keys.push((file.name.clone(), key));
+    pub fn add_file(&mut self, file: File, key: FileKey) {
+        self.file_keys.insert(file.id, key);
         self.files.push(file);
     }
 
-    pub fn add_folder(&mut self, folder: Folder, key: Vec<u8>) {
-        self.folder_keys.push((folder.name.clone(), key));
+    pub fn add_folder(&mut self, folder: Folder, key: FileKey) {
+        self.folder_keys.insert(folder.id, key);
+        self.folders.push(folder);
+    }
+
+    pub fn add_link(&mut self, link: Link) {
+        self.links.push(link);
+    }
+
+    // Path based operations work on a decrypted tree, paths are relative to this folder: "/home/report.txt" or "home/report.txt"
+    pub fn get(&self, path: &str) -> Result<Entry<'_>, SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let Some((name, parent)) = segments.split_last() else {
+            return Ok(Entry::Folder(self));
+        };
+        let parent = self.folder_at(parent, path)?;
+        if let Some(file) = parent.files.iter().find(|file| file.name == *name) {
+            return Ok(Entry::File(file));
+        }
+        if let Some(link) = parent.links.iter().find(|link| link.name == *name) {
+            return Ok(Entry::Link(link));
+        }
+        parent.folders.iter().find(|folder| folder.name == *name)
+            .map(Entry::Folder)
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Result<EntryMut<'_>, SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let Some((name, parent)) = segments.split_last() else {
+            return Ok(EntryMut::Folder(self));
+        };
+        let parent = self.folder_at_mut(parent, path)?;
+        if let Some(index) = parent.files.iter().position(|file| file.name == *name) {
+            return Ok(EntryMut::File(&mut parent.files[index]));
+        }
+        if let Some(index) = parent.links.iter().position(|link| link.name == *name) {
+            return Ok(EntryMut::Link(&mut parent.links[index]));
+        }
+        parent.folders.iter_mut().find(|folder| folder.name == *name)
+            .map(EntryMut::Folder)
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))
+    }
+
+    pub fn create_dir_all(&mut self, path: &str) -> Result<&mut Folder, SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let mut current = self;
+        for name in segments {
+            if current.files.iter().any(|file| file.name == name) || current.links.iter().any(|link| link.name == name) {
+                return Err(SafeStoreError::AlreadyExists(path.to_string()));
+            }
+            let index = match current.folders.iter().position(|folder| folder.name == name) {
+                Some(index) => index,
+                None => {
+                    let folder = Folder::new(name.to_vec(), current.owner.clone());
+                    current.add_folder(folder, FileKey::generate()?);
+                    current.folders.len() - 1
+                }
+            };
+            current = &mut current.folders[index];
+        }
+        Ok(current)
+    }
+
+    // Creates the file if needed, the parent folder has to exist
+    pub fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let (name, parent) = segments.split_last().ok_or_else(|| SafeStoreError::InvalidPath(path.to_string()))?;
+        let parent = self.folder_at_mut(parent, path)?;
+        if parent.folders.iter().any(|folder| folder.name == *name) || parent.links.iter().any(|link| link.name == *name) {
+            return Err(SafeStoreError::AlreadyExists(path.to_string()));
+        }
+        match parent.files.iter_mut().find(|file| file.name == *name) {
+            Some(file) => file.data = Zeroizing::new(data),
+            None => {
+                let file = File::new(name.to_vec(), parent.owner.clone(), data);
+                parent.add_file(file, FileKey::generate()?);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<&[u8], SafeStoreError> {
+        match self.get(path)? {
+            Entry::File(file) => Ok(&file.data),
+            Entry::Folder(_) | Entry::Link(_) => Err(SafeStoreError::NotFound(path.to_string())),
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<(), SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let (name, parent) = segments.split_last().ok_or_else(|| SafeStoreError::InvalidPath(path.to_string()))?;
+        self.folder_at_mut(parent, path)?
+            .detach(name)
+            .map(|_| ())
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))
+    }
+
+    pub fn rename(&mut self, path: &str, new_name: &str) -> Result<(), SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let (name, parent) = segments.split_last().ok_or_else(|| SafeStoreError::InvalidPath(path.to_string()))?;
+        if Folder::split_path(new_name)?.len() != 1 {
+            return Err(SafeStoreError::InvalidPath(new_name.to_string()));
+        }
+        let new_name = new_name.as_bytes();
+        let parent = self.folder_at_mut(parent, path)?;
+        if !parent.contains(name) {
+            return Err(SafeStoreError::NotFound(path.to_string()));
+        }
+        if *name == new_name {
+            return Ok(());
+        }
+        if parent.contains(new_name) {
+            return Err(SafeStoreError::AlreadyExists(String::from_utf8_lossy(new_name).to_string()));
+        }
+        // Keys are bound to the id, renaming leaves them untouched
+        if let Some(file) = parent.files.iter_mut().find(|file| file.name == *name) {
+            file.name = new_name.to_vec();
+            return Ok(());
+        }
+        if let Some(folder) = parent.folders.iter_mut().find(|folder| folder.name == *name) {
+            folder.name = new_name.to_vec();
+            return Ok(());
+        }
+        if let Some(link) = parent.links.iter_mut().find(|link| link.name == *name) {
+            link.name = new_name.to_vec();
+            return Ok(());
+        }
+        Err(SafeStoreError::NotFound(path.to_string()))
+    }
+
+    // Moves the entry at path into the existing folder new_parent, keeping its key
+    pub fn move_to(&mut self, path: &str, new_parent: &str) -> Result<(), SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let (name, parent) = segments.split_last().ok_or_else(|| SafeStoreError::InvalidPath(path.to_string()))?;
+        let destination = Folder::split_path(new_parent)?;
+        // A folder cannot be moved into itself or one of its descendants
+        if destination.starts_with(&segments) {
+            return Err(SafeStoreError::InvalidPath(new_parent.to_string()));
+        }
+        if !self.folder_at(parent, path)?.contains(name) {
+            return Err(SafeStoreError::NotFound(path.to_string()));
+        }
+        if self.folder_at(&destination, new_parent)?.contains(name) {
+            return Err(SafeStoreError::AlreadyExists(path.to_string()));
+        }
+
+        let detached = self.folder_at_mut(parent, path)?.detach(name)
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))?;
+        let destination = self.folder_at_mut(&destination, new_parent)?;
+        match detached {
+            Detached::File(file, key) => destination.add_file(file, key),
+            Detached::Folder(folder, key) => destination.add_folder(folder, key),
+            Detached::Link(link) => destination.add_link(link),
+        }
+        Ok(())
+    }
+
+    // Id of the folder at path, id of its parent and the folder key, everything a share of the folder needs
+    pub fn folder_key(&self, path: &str) -> Result<(Uuid, Uuid, &FileKey), SafeStoreError> {
+        let segments = Folder::split_path(path)?;
+        let (name, parent) = segments.split_last().ok_or_else(|| SafeStoreError::InvalidPath(path.to_string()))?;
+        let parent = self.folder_at(parent, path)?;
+        let folder = parent.folders.iter().find(|folder| folder.name == *name)
+            .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))?;
+        let key = parent.folder_keys.get(&folder.id).ok_or(SafeStoreError::MissingKey)?;
+        Ok((folder.id, parent.id, key))
+    }
+
+    // The folder holding the key of folder_id, looked up anywhere below this folder
+    pub fn parent_of_mut(&mut self, folder_id: Uuid) -> Option<&mut Folder> {
+        if self.folder_keys.contains_key(&folder_id) {
+            return Some(self);
+        }
+        self.folders.iter_mut().find_map(|folder| folder.parent_of_mut(folder_id))
+    }
+
+    // New keys for folder_id and for everything below it, e.g. after a share of it was revoked.
+    // Nothing is re-encrypted here: the next symmetric_encrypt of the tree encrypts everything under the new keys.
+    // A quarantined folder cannot be rotated, it is still encrypted under its keys.
+    pub fn rotate_keys(&mut self, folder_id: Uuid) -> Result<(), SafeStoreError> {
+        let parent = self.parent_of_mut(folder_id).ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?;
+        let folder = parent.folders.iter_mut().find(|folder| folder.id == folder_id)
+            .ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?;
+        parent.folder_keys.insert(folder.id, FileKey::generate()?);
+        if let Some(write_key) = parent.write_keys.get_mut(&folder.id) {
+            *write_key = WriteKey::generate()?;
+        }
+        folder.rotate_descendant_keys()
+    }
+
+    // Quarantined folders keep their keys, they are still encrypted under them
+    fn rotate_descendant_keys(&mut self) -> Result<(), SafeStoreError> {
+        let quarantined: Vec<Uuid> = self.quarantined.iter().map(|folder| folder.id).collect();
+        for key in self.file_keys.values_mut() {
+            *key = FileKey::generate()?;
+        }
+        for (id, key) in &mut self.folder_keys {
+            if !quarantined.contains(id) {
+                *key = FileKey::generate()?;
+            }
+        }
+        for (id, key) in &mut self.write_keys {
+            if !quarantined.contains(id) {
+                *key = WriteKey::generate()?;
+            }
+        }
+        for folder in &mut self.folders {
+            folder.rotate_descendant_keys()?;
+        }
+        Ok(())
+    }
+
+    // folder_id is a quarantined folder or below one
+    pub fn is_quarantined(&self, folder_id: Uuid) -> bool {
+        self.quarantined.iter().any(|folder| folder.find(folder_id).is_some())
+            || self.folders.iter().any(|folder| folder.is_quarantined(folder_id))
+    }
+
+    // For the owner, once they looked at what a recipient stored: the quarantined folder is decrypted without checking
+    // its signature and takes its place in the tree again, the next symmetric_encrypt signs it with its write key.
+    pub fn unquarantine(&mut self, folder_id: Uuid) -> Result<(), SafeStoreError> {
+        let Some(index) = self.quarantined.iter().position(|folder| folder.id == folder_id) else {
+            return self.folders.iter_mut()
+                .find(|folder| folder.is_quarantined(folder_id))
+                .ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?
+                .unquarantine(folder_id);
+        };
+        let folder_key = self.folder_keys.get(&folder_id).ok_or(SafeStoreError::MissingKey)?;
+        let write_key = self.write_keys.get(&folder_id).ok_or(SafeStoreError::MissingKey)?;
+        let folder = self.quarantined[index].symmetric_decrypt(folder_key.as_bytes(), Some(write_key.as_bytes()), Some(self.id))?;
+        self.quarantined.remove(index);
         self.folders.push(folder);
+        Ok(())
+    }
+
+    // Ids of this folder and of every folder below it
+    pub fn folder_ids(&self) -> Vec<Uuid> {
+        let mut ids = vec![self.id];
+        for folder in &self.folders {
+            ids.extend(folder.folder_ids());
+        }
+        ids
+    }
+
+    // Ids of the folders below this one that have a write key, i.e. that were shared. Also works on encrypted trees.
+    pub fn shared_folder_ids(&self) -> Vec<Uuid> {
+        let mut ids: Vec<Uuid> = self.write_keys.keys().copied().collect();
+        for folder in &self.folders {
+            ids.extend(folder.shared_folder_ids());
+        }
+        ids
+    }
+
+    // Lookups by id also work on encrypted trees, ids are stored in clear
+    pub fn find(&self, id: Uuid) -> Option<&Folder> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.folders.iter().find_map(|folder| folder.find(id))
+    }
+
+    pub fn find_mut(&mut self, id: Uuid) -> Option<&mut Folder> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.folders.iter_mut().find_map(|folder| folder.find_mut(id))
+    }
+
+    fn split_path(path: &str) -> Result<Vec<&[u8]>, SafeStoreError> {
+        let segments: Vec<&[u8]> = path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.as_bytes())
+            .collect();
+        if segments.iter().any(|segment| *segment == b"." || *segment == b"..") {
+            return Err(SafeStoreError::InvalidPath(path.to_string()));
+        }
+        Ok(segments)
+    }
+
+    fn folder_at(&self, segments: &[&[u8]], path: &str) -> Result<&Folder, SafeStoreError> {
+        let mut current = self;
+        for name in segments {
+            current = current.folders.iter().find(|folder| folder.name == *name)
+                .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))?;
+        }
+        Ok(current)
+    }
+
+    fn folder_at_mut(&mut self, segments: &[&[u8]], path: &str) -> Result<&mut Folder, SafeStoreError> {
+        let mut current = self;
+        for name in segments {
+            current = current.folders.iter_mut().find(|folder| folder.name == *name)
+                .ok_or_else(|| SafeStoreError::NotFound(path.to_string()))?;
+        }
+        Ok(current)
+    }
+
+    fn contains(&self, name: &[u8]) -> bool {
+        self.files.iter().any(|file| file.name == name)
+            || self.folders.iter().any(|folder| folder.name == name)
+            || self.links.iter().any(|link| link.name == name)
+    }
+
+    // Removes a direct child and its key entry
+    fn detach(&mut self, name: &[u8]) -> Option<Detached> {
+        if let Some(index) = self.files.iter().position(|file| file.name == name) {
+            let key = self.file_keys.remove(&self.files[index].id)?;
+            return Some(Detached::File(self.files.remove(index), key));
+        }
+        if let Some(index) = self.folders.iter().position(|folder| folder.name == name) {
+            // Shares of a moved folder stop opening anyway, its write key goes with them
+            self.write_keys.remove(&self.folders[index].id);
+            self.verify_keys.remove(&self.folders[index].id);
+            let key = self.folder_keys.remove(&self.folders[index].id)?;
+            return Some(Detached::Folder(self.folders.remove(index), key));
+        }
+        if let Some(index) = self.links.iter().position(|link| link.name == name) {
+            return Some(Detached::Link(self.links.remove(index)));
+        }
+        None
     }
 
     pub fn display(&self, level: usize) -> String {
@@ -54,14 +433,24 @@ impl Folder {
         }
         
         for (i, folder) in self.folders.iter().enumerate() {
-            let is_last = i == self.folders.len() - 1 && self.files.is_empty();
+            let is_last = i == self.folders.len() - 1 && self.quarantined.is_empty() && self.links.is_empty() && self.files.is_empty();
             _display.push_str(&folder.display_nested(level + 1, is_last));
         }
+
+        for folder in &self.quarantined {
+            _display.push_str(&folder.display_quarantined(level + 1));
+        }
+
+        for (i, link) in self.links.iter().enumerate() {
+            let is_last = i == self.links.len() - 1 && self.files.is_empty();
+            _display.push_str(&link.display_nested(level + 1, is_last));
+            _display.push('\n');
+        }
         
         for (i, file) in self.files.iter().enumerate() {
             let is_last = i == self.files.len() - 1;
             _display.push_str(&file.display_nested(level + 1, is_last));
-            _display.push_str("\n");
+            _display.push('\n');
         }
 
         _display
@@ -72,234 +461,488 @@ impl Folder {
         let mut display = format!("{}├── Folder: {:?}\n", indent, String::from_utf8_lossy(&self.name));
 
         for (i, folder) in self.folders.iter().enumerate() {
-            let is_last = i == self.folders.len() - 1 && self.files.is_empty();
+            let is_last = i == self.folders.len() - 1 && self.quarantined.is_empty() && self.links.is_empty() && self.files.is_empty();
             display.push_str(&folder.display_nested(level + 1, is_last));
         }
+
+        for folder in &self.quarantined {
+            display.push_str(&folder.display_quarantined(level + 1));
+        }
+
+        for (i, link) in self.links.iter().enumerate() {
+            let is_last = i == self.links.len() - 1 && self.files.is_empty();
+            display.push_str(&link.display_nested(level + 1, is_last));
+            display.push('\n');
+        }
         
         for (i, file) in self.files.iter().enumerate() {
             let is_last = i == self.files.len() - 1;
             display.push_str(&file.display_nested(level + 1, is_last));
-            display.push_str("\n");
+            display.push('\n');
         }
 
         display
     }
 
-    pub fn symmetric_encrypt(&self, key: Vec<u8>, is_root: bool) -> Folder {
-        // We need to encrypt: name, owner, uid, files, folders
-        let mut _encrypted_name = Vec::new();
-        if !is_root {
-            _encrypted_name = cryptography::symmetric_encrypt(&key, self.name.clone());
+    fn display_quarantined(&self, level: usize) -> String {
+        let indent = "│   ".repeat(level - 1) + "│   ";
+        format!("{}├── Quarantined shared folder: {}\n", indent, self.id)
+    }
+
+    // parent is the id of the folder this one is stored in, None for a root folder
+    // padding applies to names, owners and contents, the whole tree uses the same policy
+    // wrap_key wraps the write keys of the shared sub folders: the write key of this folder or of the nearest shared one above,
+    // the master key for a root folder. Without it a tree that holds shared sub folders cannot be encrypted.
+    pub fn symmetric_encrypt(&self, key: &[u8], wrap_key: Option<&[u8]>, parent: Option<Uuid>, padding: PaddingPolicy) -> Result<Folder, SafeStoreError> {
+        // We need to encrypt: name, owner, files, folders and their keys, the ids stay in clear
+        let encrypted_name = if parent.is_some() {
+            cryptography::symmetric_encrypt_padded(key, &self.name, &AssociatedData::new(self.id, Field::Name, parent), padding)?
         } else {
-            _encrypted_name = self.name.clone();
-        }
-        let encrypted_owner = cryptography::symmetric_encrypt(&key, self.owner.clone());
+            self.name.clone()
+        };
+        let encrypted_owner = cryptography::symmetric_encrypt_padded(key, &self.owner, &AssociatedData::new(self.id, Field::Owner, parent), padding)?;
 
         let mut encrypted_files: Vec<File> = Vec::new();
         let mut encrypted_folders: Vec<Folder> = Vec::new();
-        let mut encrypted_file_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        let mut encrypted_folder_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        
-        for &(ref name, ref file_key) in &self.file_keys {
+        let mut encrypted_file_keys: BTreeMap<Uuid, FileKey> = BTreeMap::new();
+        let mut encrypted_folder_keys: BTreeMap<Uuid, FileKey> = BTreeMap::new();
+        let mut encrypted_write_keys: BTreeMap<Uuid, WriteKey> = BTreeMap::new();
+        let mut verify_keys: BTreeMap<Uuid, [u8; 32]> = BTreeMap::new();
+
+        for file in &self.files {
             // The file itself
-            let file = self.files.iter().find(|file| file.name == *name).unwrap();
-            let encrypted_file = file.symmetric_encrypt(file_key.clone());
-            let encrypted_name = encrypted_file.name.clone();
-            encrypted_files.push(encrypted_file);
+            let file_key = self.file_keys.get(&file.id).ok_or(SafeStoreError::MissingKey)?;
+            encrypted_files.push(file.symmetric_encrypt(file_key.as_bytes(), self.id, padding)?);
 
             // And its key
-            let encrypted_file_key = cryptography::symmetric_encrypt(&key, file_key.clone());
-            encrypted_file_keys.push((encrypted_name, encrypted_file_key));
+            let aad = AssociatedData::new(file.id, Field::FileKey, Some(self.id));
+            let encrypted_file_key = cryptography::symmetric_encrypt(key, file_key.as_bytes(), &aad)?;
+            encrypted_file_keys.insert(file.id, FileKey::new(encrypted_file_key));
         }
 
-        for &(ref name, ref folder_key) in &self.folder_keys {
+        for folder in &self.folders {
             // The folder itself
-            let folder = self.folders.iter().find(|folder| folder.name == *name).unwrap();
-            let encrypted_folder = folder.symmetric_encrypt(folder_key.clone(), false);
-            let encrypted_name = encrypted_folder.name.clone();
+            let folder_key = self.folder_keys.get(&folder.id).ok_or(SafeStoreError::MissingKey)?;
+            let write_key = self.write_keys.get(&folder.id);
+            if write_key.is_none() && self.verify_keys.contains_key(&folder.id) {
+                return Err(SafeStoreError::MissingKey);
+            }
+            let folder_wrap_key = write_key.map(|write_key| write_key.as_bytes()).or(wrap_key);
+            let mut encrypted_folder = folder.symmetric_encrypt(folder_key.as_bytes(), folder_wrap_key, Some(self.id), padding)?;
+
+            // Signed if it was shared, and its write key wrapped under the wrap key
+            if let Some(write_key) = write_key {
+                encrypted_folder.sign_with(write_key)?;
+                encrypted_write_keys.insert(folder.id, self.wrap_write_key(wrap_key, folder.id, write_key)?);
+                verify_keys.insert(folder.id, cryptography::write_public_key(write_key)?);
+            }
             encrypted_folders.push(encrypted_folder);
 
             // And its key
-            let encrypted_folder_key = cryptography::symmetric_encrypt(&key, folder_key.clone());
-            encrypted_folder_keys.push((encrypted_name, encrypted_folder_key));
+            let aad = AssociatedData::new(folder.id, Field::FolderKey, Some(self.id));
+            let encrypted_folder_key = cryptography::symmetric_encrypt(key, folder_key.as_bytes(), &aad)?;
+            encrypted_folder_keys.insert(folder.id, FileKey::new(encrypted_folder_key));
         }
 
-        Folder {
-            name: _encrypted_name,
+        // Already encrypted, and not signed again: only the write key's holders can make them valid
+        for folder in &self.quarantined {
+            let folder_key = self.folder_keys.get(&folder.id).ok_or(SafeStoreError::MissingKey)?;
+            let write_key = self.write_keys.get(&folder.id).ok_or(SafeStoreError::MissingKey)?;
+            let aad = AssociatedData::new(folder.id, Field::FolderKey, Some(self.id));
+            encrypted_folder_keys.insert(folder.id, FileKey::new(cryptography::symmetric_encrypt(key, folder_key.as_bytes(), &aad)?));
+            encrypted_write_keys.insert(folder.id, self.wrap_write_key(wrap_key, folder.id, write_key)?);
+            verify_keys.insert(folder.id, cryptography::write_public_key(write_key)?);
+            encrypted_folders.push(folder.duplicate());
+        }
+
+        Ok(Folder {
+            id: self.id,
+            name: encrypted_name,
             owner: encrypted_owner,
             files: encrypted_files,
             folders: encrypted_folders,
+            links: self.links.iter().map(|link| link.symmetric_encrypt(key, self.id, padding)).collect::<Result<_, _>>()?,
             signature: self.signature.clone(),
             file_keys: encrypted_file_keys,
             folder_keys: encrypted_folder_keys,
-        }
+            write_keys: encrypted_write_keys,
+            verify_keys,
+            quarantined: Vec::new(),
+        })
     }
 
-    pub fn symmetric_decrypt(&self, key: Vec<u8>, is_root: bool) -> Folder {
-        // We need to decrypt: name, owner, uid, files, folders
-        let mut _decrypted_name = Vec::new();
-        if !is_root {
-            _decrypted_name = cryptography::symmetric_decrypt(&key, self.name.clone());
+    fn wrap_write_key(&self, wrap_key: Option<&[u8]>, folder_id: Uuid, write_key: &WriteKey) -> Result<WriteKey, SafeStoreError> {
+        let wrap_key = wrap_key.ok_or(SafeStoreError::MissingKey)?;
+        let aad = AssociatedData::new(folder_id, Field::WriteKey, Some(self.id));
+        Ok(WriteKey::new(cryptography::symmetric_encrypt(wrap_key, write_key.as_bytes(), &aad)?))
+    }
+
+    // parent and wrap_key have to be the ones given to symmetric_encrypt, otherwise decryption fails.
+    // Without wrap_key the write keys of the shared sub folders stay out of the decrypted tree, they are checked with verify_keys.
+    pub fn symmetric_decrypt(&self, key: &[u8], wrap_key: Option<&[u8]>, parent: Option<Uuid>) -> Result<Folder, SafeStoreError> {
+        // We need to decrypt: name, owner, files, folders and their keys
+        let decrypted_name = if parent.is_some() {
+            cryptography::symmetric_decrypt(key, &self.name, &AssociatedData::new(self.id, Field::Name, parent))?
         } else {
-            _decrypted_name = self.name.clone();
-        }
-        let decrypted_owner = cryptography::symmetric_decrypt(&key, self.owner.clone());
+            self.name.clone()
+        };
+        let decrypted_owner = cryptography::symmetric_decrypt(key, &self.owner, &AssociatedData::new(self.id, Field::Owner, parent))?;
 
         let mut decrypted_files: Vec<File> = Vec::new();
         let mut decrypted_folders: Vec<Folder> = Vec::new();
-        let mut decrypted_file_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        let mut decrypted_folder_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut decrypted_file_keys: BTreeMap<Uuid, FileKey> = BTreeMap::new();
+        let mut decrypted_folder_keys: BTreeMap<Uuid, FileKey> = BTreeMap::new();
+        let mut decrypted_write_keys: BTreeMap<Uuid, WriteKey> = BTreeMap::new();
+        let mut quarantined: Vec<Folder> = Vec::new();
         
         for enc_file in &self.files {
             // First we get the encrypted file key
-            let (_file_name, file_key) = self.file_keys.iter().find(|(uid, _)| uid == &enc_file.name).unwrap();
-            let decrypted_file_key = cryptography::symmetric_decrypt(&key, file_key.clone());
+            let file_key = self.file_keys.get(&enc_file.id).ok_or(SafeStoreError::MissingKey)?;
+            let aad = AssociatedData::new(enc_file.id, Field::FileKey, Some(self.id));
+            let decrypted_file_key = FileKey::new(cryptography::symmetric_decrypt(key, file_key.as_bytes(), &aad)?);
             
             // Then we decrypt the file
-            let decrypted_file = enc_file.symmetric_decrypt(decrypted_file_key.clone());
-            let decrypted_name = decrypted_file.name.clone();
-            decrypted_files.push(decrypted_file);
-            decrypted_file_keys.push((decrypted_name, decrypted_file_key));
-
+            decrypted_files.push(enc_file.symmetric_decrypt(decrypted_file_key.as_bytes(), self.id)?);
+            decrypted_file_keys.insert(enc_file.id, decrypted_file_key);
         }
 
         for enc_folder in &self.folders {
             // First we get the encrypted folder key
-            let (_folder_uid, folder_key) = self.folder_keys.iter().find(|(uid, _)| uid == &enc_folder.name).unwrap();
-            let decrypted_folder_key = cryptography::symmetric_decrypt(&key, folder_key.clone());
-            
-            // Then we decrypt the folder
-            let decrypted_folder = enc_folder.symmetric_decrypt(decrypted_folder_key.clone(), false);
-            let decrypted_name = decrypted_folder.name.clone();
+            let folder_key = self.folder_keys.get(&enc_folder.id).ok_or(SafeStoreError::MissingKey)?;
+            let aad = AssociatedData::new(enc_folder.id, Field::FolderKey, Some(self.id));
+            let decrypted_folder_key = FileKey::new(cryptography::symmetric_decrypt(key, folder_key.as_bytes(), &aad)?);
+
+            // A shared folder has to carry a valid signature, whoever modified it without its write key is ignored.
+            // Recipients can upload it, so a shared folder that fails is quarantined rather than failing the whole tree.
+            let decrypted_folder = match self.write_keys.get(&enc_folder.id) {
+                Some(write_key) => {
+                    let aad = AssociatedData::new(enc_folder.id, Field::WriteKey, Some(self.id));
+                    let decrypted_write_key = wrap_key
+                        .map(|wrap_key| cryptography::symmetric_decrypt(wrap_key, write_key.as_bytes(), &aad).map(WriteKey::new))
+                        .transpose()?;
+                    let verify_key = match &decrypted_write_key {
+                        Some(write_key) => cryptography::write_public_key(write_key),
+                        None => self.verify_keys.get(&enc_folder.id).copied().ok_or(SafeStoreError::MissingKey),
+                    };
+                    let folder_wrap_key = decrypted_write_key.as_ref().map(|write_key| write_key.as_bytes());
+                    let decrypted = verify_key
+                        .and_then(|verify_key| enc_folder.verify_with(&verify_key))
+                        .and_then(|_| enc_folder.symmetric_decrypt(decrypted_folder_key.as_bytes(), folder_wrap_key, Some(self.id)));
+                    if let Some(write_key) = decrypted_write_key {
+                        decrypted_write_keys.insert(enc_folder.id, write_key);
+                    }
+                    match decrypted {
+                        Ok(folder) => folder,
+                        Err(_) => {
+                            quarantined.push(enc_folder.duplicate());
+                            decrypted_folder_keys.insert(enc_folder.id, decrypted_folder_key);
+                            continue;
+                        }
+                    }
+                }
+                None => enc_folder.symmetric_decrypt(decrypted_folder_key.as_bytes(), wrap_key, Some(self.id))?,
+            };
+
+            // Then we keep the folder
             decrypted_folders.push(decrypted_folder);
-            decrypted_folder_keys.push((decrypted_name, decrypted_folder_key));
+            decrypted_folder_keys.insert(enc_folder.id, decrypted_folder_key);
         }
 
-        Folder {
-            name: _decrypted_name,
+        Ok(Folder {
+            id: self.id,
+            name: decrypted_name,
             owner: decrypted_owner,
             files: decrypted_files,
             folders: decrypted_folders,
+            links: self.links.iter().map(|link| link.symmetric_decrypt(key, self.id)).collect::<Result<_, _>>()?,
             signature: self.signature.clone(),
             file_keys: decrypted_file_keys,
             folder_keys: decrypted_folder_keys,
-        }
+            write_keys: decrypted_write_keys,
+            verify_keys: self.verify_keys.clone(),
+            quarantined,
+        })
     }
 
-    pub fn asymmetric_encrypt(&self, receiver: (PublicKey, SecretKey), sender: (PublicKey, SecretKey)) -> Folder {
-        // We need to encrypt: name, owner, uid, files, folders
-        // The function will return ciphertexts and nonces for each encryption
-        let encrypted_name = cryptography::asymmetric_encrypt(sender.1, receiver.0, self.name.clone());
-        let encrypted_owner = cryptography::asymmetric_encrypt(sender.1, receiver.0, self.owner.clone());
-        let mut encrypted_files: Vec<File> = Vec::new();
-        let mut encrypted_folders: Vec<Folder> = Vec::new();
-        let mut encrypted_file_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        let mut encrypted_folder_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    pub fn sign(&mut self, keys: &user::UserKeys) -> Result<(), SafeStoreError> {
+        let signature = keys.signing_keypair.sign_with_defaults(self.to_bytes())
+            .map_err(|_| SafeStoreError::SignatureInvalid)?;
+        self.signature = signature.to_bytes();
+        Ok(())
+    }
 
-        for &(ref name, ref file_key) in &self.file_keys {
-            // The file itself
-            let file = self.files.iter().find(|file| file.name == *name).unwrap();
-            let encrypted_file = file.asymmetric_encrypt(receiver, sender);
-            let encrypted_name = encrypted_file.name.clone();
-            encrypted_files.push(encrypted_file);
+    pub fn verify(&self, user: &user::User) -> Result<(), SafeStoreError> {
+        let signature: SignedMessage<StackByteArray<64>, Vec<u8>> = SignedMessage::from_bytes(&self.signature)
+            .map_err(|_| SafeStoreError::SignatureInvalid)?;
+        signature.verify(&StackByteArray::from(user.signing_public_key))
+            .map_err(|_| SafeStoreError::SignatureInvalid)
+    }
 
-            // And its key
-            let encrypted_file_key = cryptography::asymmetric_encrypt(sender.1, receiver.0, file_key.clone());
-            encrypted_file_keys.push((encrypted_name, encrypted_file_key));
+    // Signs the whole encoding of an encrypted folder, keys and sub folders included, except the signature itself.
+    // Shared sub folders are only covered by their id and verify key, their writers can change them without this signature.
+    pub fn sign_with(&mut self, write_key: &WriteKey) -> Result<(), SafeStoreError> {
+        self.signature = Vec::new();
+        self.signature = cryptography::sign_detached(write_key, &self.signed_bytes())?;
+        Ok(())
+    }
+
+    pub fn verify_with(&self, public_key: &[u8; 32]) -> Result<(), SafeStoreError> {
+        cryptography::verify_detached(public_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // Hash of an encrypted folder as stored, it changes with every upload since every encryption uses fresh nonces.
+    // Uploads name the etag of the version they were made from, so that they cannot overwrite a newer one.
+    pub fn etag(&self) -> Result<[u8; 32], SafeStoreError> {
+        let mut writer = Writer::new();
+        self.encode(&mut writer);
+        let mut etag = [0u8; 32];
+        crypto_generichash(&mut etag, &writer.into_bytes(), None)
+            .map_err(|err| SafeStoreError::Malformed(err.to_string()))?;
+        Ok(etag)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.duplicate();
+        unsigned.signature = Vec::new();
+        unsigned.strip_shared_folders();
+        let mut writer = Writer::new();
+        unsigned.encode(&mut writer);
+        writer.into_bytes()
+    }
+
+    fn strip_shared_folders(&mut self) {
+        for folder in &mut self.folders {
+            if self.verify_keys.contains_key(&folder.id) {
+                *folder = Folder { id: folder.id, ..Folder::new(Vec::new(), Vec::new()) };
+            } else {
+                folder.strip_shared_folders();
+            }
         }
+    }
 
-        for &(ref name, ref folder_key) in &self.folder_keys {
-            // The folder itself
-            let folder = self.folders.iter().find(|folder| folder.name == *name).unwrap();
-            let encrypted_folder = folder.asymmetric_encrypt(receiver, sender);
-            let encrypted_name = encrypted_folder.name.clone();
-            encrypted_folders.push(encrypted_folder);
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(&self.owner);
 
-            // And its key
-            let encrypted_folder_key = cryptography::asymmetric_encrypt(sender.1, receiver.0, folder_key.clone());
-            encrypted_folder_keys.push((encrypted_name, encrypted_folder_key));
+        for file in &self.files {
+            bytes.extend_from_slice(&file.to_bytes());
         }
 
-        Folder {
-            name: encrypted_name,
-            owner: encrypted_owner,
-            files: encrypted_files,
-            folders: encrypted_folders,
-            signature: self.signature.clone(),
-            file_keys: encrypted_file_keys,
-            folder_keys: encrypted_folder_keys,
+        for folder in &self.folders {
+            bytes.extend_from_slice(&folder.to_bytes());
         }
+
+        bytes
     }
 
-    pub fn asymmetric_decrypt(&self, receiver: (PublicKey, SecretKey), sender: (PublicKey, SecretKey)) -> Folder {
-        // We need to decrypt: name, owner, uid, files, folders
-        let mut decrypted_files: Vec<File> = Vec::new();
-        let mut decrypted_folders: Vec<Folder> = Vec::new();
-        let mut decrypted_file_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        let mut decrypted_folder_keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_raw(self.id.as_bytes());
+        writer.write_bytes(&self.name);
+        writer.write_bytes(&self.owner);
+        writer.write_bytes(&self.signature);
 
-        let decrypted_name = cryptography::asymmetric_decrypt(sender.0, receiver.1, self.name.clone());
-        let decrypted_owner = cryptography::asymmetric_decrypt(sender.0, receiver.1, self.owner.clone());
+        writer.write_u64(self.files.len() as u64);
+        for file in &self.files {
+            file.encode(writer);
+        }
 
-        for enc_file in &self.files {
-            // First we get the encrypted file key
-            let (_file_name, file_key) = self.file_keys.iter().find(|(uid, _)| uid == &enc_file.name).unwrap();
-            let decrypted_file_key = cryptography::asymmetric_decrypt(sender.0, receiver.1, file_key.clone());
-            
-            // Then we decrypt the file
-            let decrypted_file = enc_file.asymmetric_decrypt(sender, receiver);
-            let decrypted_name = decrypted_file.name.clone();
-            decrypted_files.push(decrypted_file);
-            decrypted_file_keys.push((decrypted_name, decrypted_file_key));
+        writer.write_u64(self.folders.len() as u64);
+        for folder in &self.folders {
+            folder.encode(writer);
+        }
 
+        writer.write_u64(self.file_keys.len() as u64);
+        for (id, key) in &self.file_keys {
+            writer.write_raw(id.as_bytes());
+            writer.write_bytes(key.as_bytes());
         }
 
-        for enc_folder in &self.folders {
-            // First we get the encrypted folder key
-            let (_folder_uid, folder_key) = self.folder_keys.iter().find(|(uid, _)| uid == &enc_folder.name).unwrap();
-            let decrypted_folder_key = cryptography::asymmetric_decrypt(sender.0, receiver.1, folder_key.clone());
-            
-            // Then we decrypt the folder
-            let decrypted_folder = enc_folder.asymmetric_decrypt(sender, receiver);
-            let decrypted_name = decrypted_folder.name.clone();
-            decrypted_folders.push(decrypted_folder);
-            decrypted_folder_keys.push((decrypted_name, decrypted_folder_key));
+        writer.write_u64(self.folder_keys.len() as u64);
+        for (id, key) in &self.folder_keys {
+            writer.write_raw(id.as_bytes());
+            writer.write_bytes(key.as_bytes());
         }
 
-        Folder {
-            name: decrypted_name,
-            owner: decrypted_owner,
-            files: decrypted_files,
-            folders: decrypted_folders,
-            signature: self.signature.clone(),
-            file_keys: decrypted_file_keys,
-            folder_keys: decrypted_folder_keys,
+        writer.write_u64(self.write_keys.len() as u64);
+        for (id, key) in &self.write_keys {
+            writer.write_raw(id.as_bytes());
+            writer.write_bytes(key.as_bytes());
         }
-    }
 
-    pub fn sign(&mut self, user: &user::User) {
-        let signature = user.signing_keypair.sign_with_defaults(self.to_bytes()).expect("Error signing");
-        self.signature = signature.to_bytes();
-    }
+        writer.write_u64(self.verify_keys.len() as u64);
+        for (id, key) in &self.verify_keys {
+            writer.write_raw(id.as_bytes());
+            writer.write_bytes(key);
+        }
 
-    pub fn verify(&self, user: &user::User) -> bool {
-        let signature: SignedMessage<StackByteArray<64>, Vec<u8>> = SignedMessage::from_bytes(&self.signature).expect("Error parsing signature");
-        signature.verify(&user.signing_keypair.public_key).is_ok()
+        writer.write_u64(self.links.len() as u64);
+        for link in &self.links {
+            link.encode(writer);
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.name);
-        bytes.extend_from_slice(&self.owner);
+    pub fn decode(reader: &mut Reader) -> Result<Folder, SafeStoreError> {
+        let id = reader.read_uuid()?;
+        let mut folder = Folder::new(reader.read_bytes()?, reader.read_bytes()?);
+        folder.id = id;
+        folder.signature = reader.read_bytes()?;
 
-        for file in &self.files {
-            bytes.extend_from_slice(&file.to_bytes());
+        for _ in 0..reader.read_u64()? {
+            folder.files.push(File::decode(reader)?);
         }
 
-        for folder in &self.folders {
-            bytes.extend_from_slice(&folder.to_bytes());
+        for _ in 0..reader.read_u64()? {
+            folder.folders.push(Folder::decode(reader)?);
         }
 
-        bytes
+        for _ in 0..reader.read_u64()? {
+            folder.file_keys.insert(reader.read_uuid()?, FileKey::new(reader.read_bytes()?));
+        }
+
+        for _ in 0..reader.read_u64()? {
+            folder.folder_keys.insert(reader.read_uuid()?, FileKey::new(reader.read_bytes()?));
+        }
+
+        for _ in 0..reader.read_u64()? {
+            folder.write_keys.insert(reader.read_uuid()?, WriteKey::new(reader.read_bytes()?));
+        }
+
+        for _ in 0..reader.read_u64()? {
+            folder.verify_keys.insert(reader.read_uuid()?, reader.read_array()?);
+        }
+
+        for _ in 0..reader.read_u64()? {
+            folder.links.push(Link::decode(reader)?);
+        }
+
+        Ok(folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_shared_folder_is_quarantined() {
+        let master_key = FileKey::generate().unwrap();
+        let mut root = Folder::new(b"root".to_vec(), b"owner".to_vec());
+        root.create_dir_all("/home/shared").unwrap();
+        root.write_file("/notes.txt", b"data".to_vec()).unwrap();
+        let home_id = root.folders[0].id;
+        let shared_id = root.folders[0].folders[0].id;
+        root.folders[0].write_keys.insert(shared_id, WriteKey::generate().unwrap());
+        let mut enc_root = root.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, PaddingPolicy::default()).unwrap();
+        let signature = std::mem::take(&mut enc_root.folders[0].folders[0].signature);
+
+        // The rest of the tree still decrypts, the shared folder is kept aside as it was stored
+        let mut decrypted = enc_root.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None).unwrap();
+        assert!(decrypted.folders[0].folders.is_empty());
+        assert_eq!(decrypted.folders[0].quarantined[0].id, shared_id);
+        assert_eq!(decrypted.read_file("/notes.txt").unwrap(), b"data");
+        assert!(matches!(decrypted.rotate_keys(shared_id), Err(SafeStoreError::NotFound(_))));
+        // Rotating the folder holding it leaves its keys alone
+        let folder_key = decrypted.folders[0].folder_keys[&shared_id].as_bytes().to_vec();
+        let write_key = decrypted.folders[0].write_keys[&shared_id].as_bytes().to_vec();
+        decrypted.rotate_keys(home_id).unwrap();
+        assert_eq!(decrypted.folders[0].folder_keys[&shared_id].as_bytes(), folder_key.as_slice());
+        assert_eq!(decrypted.folders[0].write_keys[&shared_id].as_bytes(), write_key.as_slice());
+        decrypted.write_file("/more.txt", b"more".to_vec()).unwrap();
+
+        let mut stored = decrypted.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, PaddingPolicy::default()).unwrap();
+        assert_eq!(stored.folders[0].folders[0].etag().unwrap(), enc_root.folders[0].folders[0].etag().unwrap());
+        stored.folders[0].folders[0].signature = signature;
+        let restored = stored.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None).unwrap();
+        assert!(restored.folders[0].quarantined.is_empty());
+        assert_eq!(restored.folders[0].folders[0].id, shared_id);
+    }
+
+    #[test]
+    fn owner_takes_back_a_quarantined_folder() {
+        let master_key = FileKey::generate().unwrap();
+        let mut root = Folder::new(b"root".to_vec(), b"owner".to_vec());
+        root.create_dir_all("/shared").unwrap();
+        root.write_file("/shared/file.txt", b"data".to_vec()).unwrap();
+        let shared_id = root.folders[0].id;
+        root.write_keys.insert(shared_id, WriteKey::generate().unwrap());
+        let mut enc_root = root.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, PaddingPolicy::default()).unwrap();
+        enc_root.folders[0].signature = Vec::new();
+
+        let mut decrypted = enc_root.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None).unwrap();
+        assert!(decrypted.is_quarantined(shared_id));
+        decrypted.unquarantine(shared_id).unwrap();
+        assert_eq!(decrypted.read_file("/shared/file.txt").unwrap(), b"data");
+        assert!(matches!(decrypted.unquarantine(shared_id), Err(SafeStoreError::NotFound(_))));
+
+        // Signed again, so it passes the check from now on
+        let stored = decrypted.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, PaddingPolicy::default()).unwrap();
+        let restored = stored.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None).unwrap();
+        assert!(!restored.is_quarantined(shared_id));
+        assert_eq!(restored.read_file("/shared/file.txt").unwrap(), b"data");
+    }
+
+    #[test]
+    fn file_moved_between_folders_does_not_decrypt() {
+        let master_key = FileKey::generate().unwrap();
+        let mut root = Folder::new(b"root".to_vec(), b"owner".to_vec());
+        root.create_dir_all("/a").unwrap();
+        root.create_dir_all("/b").unwrap();
+        root.write_file("/a/file.txt", b"data".to_vec()).unwrap();
+        let mut enc_root = root.symmetric_encrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None, PaddingPolicy::default()).unwrap();
+
+        // The server moves the encrypted file, and its wrapped key, from /a to /b
+        let (a, b) = enc_root.folders.split_at_mut(1);
+        let (a, b) = (&mut a[0], &mut b[0]);
+        let file = a.files.remove(0);
+        let file_key = a.file_keys.remove(&file.id).unwrap();
+        b.file_keys.insert(file.id, file_key);
+        b.files.push(file);
+        assert!(matches!(enc_root.symmetric_decrypt(master_key.as_bytes(), Some(master_key.as_bytes()), None), Err(SafeStoreError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn entries_are_moved_and_renamed_in_place() {
+        let mut root = Folder::new(b"root".to_vec(), b"owner".to_vec());
+        root.create_dir_all("/a/b").unwrap();
+        root.create_dir_all("/c").unwrap();
+        root.write_file("/a/file.txt", b"data".to_vec()).unwrap();
+
+        root.rename("/a/file.txt", "notes.txt").unwrap();
+        assert_eq!(root.read_file("/a/notes.txt").unwrap(), b"data");
+        root.rename("/a/notes.txt", "notes.txt").unwrap();
+        root.move_to("/a/notes.txt", "/c").unwrap();
+        assert_eq!(root.read_file("/c/notes.txt").unwrap(), b"data");
+        assert!(matches!(root.read_file("/a/notes.txt"), Err(SafeStoreError::NotFound(_))));
+        root.move_to("/a/b", "/c").unwrap();
+        assert!(matches!(root.get("/c/b"), Ok(Entry::Folder(_))));
+
+        // Folders cannot end up inside themselves
+        assert!(matches!(root.move_to("/c", "/c/b"), Err(SafeStoreError::InvalidPath(_))));
+        assert!(matches!(root.rename("/c", "a/b"), Err(SafeStoreError::InvalidPath(_))));
+    }
+
+    #[test]
+    fn missing_and_conflicting_paths_are_rejected() {
+        let mut root = Folder::new(b"root".to_vec(), b"owner".to_vec());
+        root.create_dir_all("/a").unwrap();
+        root.create_dir_all("/b").unwrap();
+        root.write_file("/a/file.txt", b"data".to_vec()).unwrap();
+        root.write_file("/b/file.txt", b"other".to_vec()).unwrap();
+
+        assert!(matches!(root.rename("/a/missing", "missing"), Err(SafeStoreError::NotFound(_))));
+        assert!(matches!(root.rename("/a/missing", "other"), Err(SafeStoreError::NotFound(_))));
+        assert!(matches!(root.rename("/missing/file.txt", "file.txt"), Err(SafeStoreError::NotFound(_))));
+        assert!(matches!(root.move_to("/a/missing", "/b"), Err(SafeStoreError::NotFound(_))));
+        assert!(matches!(root.move_to("/a/file.txt", "/missing"), Err(SafeStoreError::NotFound(_))));
+        assert!(matches!(root.remove("/a/missing"), Err(SafeStoreError::NotFound(_))));
+
+        assert!(matches!(root.rename("/a", "b"), Err(SafeStoreError::AlreadyExists(_))));
+        assert!(matches!(root.move_to("/a/file.txt", "/b"), Err(SafeStoreError::AlreadyExists(_))));
+        assert!(matches!(root.write_file("/a", b"data".to_vec()), Err(SafeStoreError::AlreadyExists(_))));
+        // Nothing was changed by the failed operations
+        assert_eq!(root.read_file("/a/file.txt").unwrap(), b"data");
+        assert_eq!(root.read_file("/b/file.txt").unwrap(), b"other");
     }
 }
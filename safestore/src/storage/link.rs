@@ -0,0 +1,73 @@
+use uuid::Uuid;
+
+use crate::cryptography::{self, AssociatedData, Field, PaddingPolicy};
+use crate::error::SafeStoreError;
+use super::encoding::{Reader, Writer};
+
+// Entry of a recipient's tree standing for a folder someone shared with them, e.g. /shared/Alice/home.
+// It only holds the id of the share, the folder itself is looked up on the server each time the link is opened,
+// so a link keeps working when the owner rotates the folder key and stops working once the share is revoked.
+#[derive(Debug, Clone)]
+pub struct Link {
+    // Random and stable across encryption, like the ids of files
+    pub id: Uuid,
+    pub name: Vec<u8>,
+    // Name of the user who owns the shared folder
+    pub owner: Vec<u8>,
+    pub share_id: Uuid,
+}
+
+impl Link {
+    pub fn new(name: Vec<u8>, owner: Vec<u8>, share_id: Uuid) -> Link {
+        Link {
+            id: Uuid::new_v4(),
+            name,
+            owner,
+            share_id,
+        }
+    }
+
+    pub fn display_nested(&self, level: usize, is_last: bool) -> String {
+        let indent = "│   ".repeat(level - 1) + if is_last { "    " } else { "│   " };
+        format!("{}├── Shared link: {} -> shared by {}", indent, String::from_utf8_lossy(&self.name), String::from_utf8_lossy(&self.owner))
+    }
+
+    // Links have no key of their own, they are encrypted under the key of the folder holding them
+    pub fn symmetric_encrypt(&self, key: &[u8], parent: Uuid, padding: PaddingPolicy) -> Result<Link, SafeStoreError> {
+        Ok(Link {
+            id: self.id,
+            name: cryptography::symmetric_encrypt_padded(key, &self.name, &self.aad(Field::Name, parent), padding)?,
+            owner: cryptography::symmetric_encrypt_padded(key, &self.owner, &self.aad(Field::Owner, parent), padding)?,
+            share_id: self.share_id,
+        })
+    }
+
+    pub fn symmetric_decrypt(&self, key: &[u8], parent: Uuid) -> Result<Link, SafeStoreError> {
+        Ok(Link {
+            id: self.id,
+            name: cryptography::symmetric_decrypt(key, &self.name, &self.aad(Field::Name, parent))?,
+            owner: cryptography::symmetric_decrypt(key, &self.owner, &self.aad(Field::Owner, parent))?,
+            share_id: self.share_id,
+        })
+    }
+
+    fn aad(&self, field: Field, parent: Uuid) -> AssociatedData {
+        AssociatedData::new(self.id, field, Some(parent))
+    }
+
+    pub fn encode(&self, writer: &mut Writer) {
+        writer.write_raw(self.id.as_bytes());
+        writer.write_bytes(&self.name);
+        writer.write_bytes(&self.owner);
+        writer.write_raw(self.share_id.as_bytes());
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<Link, SafeStoreError> {
+        Ok(Link {
+            id: reader.read_uuid()?,
+            name: reader.read_bytes()?,
+            owner: reader.read_bytes()?,
+            share_id: reader.read_uuid()?,
+        })
+    }
+}
@@ -1,3 +1,5 @@
+pub mod encoding;
 pub mod file;
 pub mod folder;
-pub mod server;
\ No newline at end of file
+pub mod link;
+pub mod server;
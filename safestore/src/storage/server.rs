@@ -1,11 +1,25 @@
-use core::panic;
+use std::fs;
+use std::path::Path;
 
+use super::encoding::{Reader, Writer};
 use super::folder::Folder;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::authentication::limiter::{Clock, LimiterPolicy, LoginLimiter, SystemClock};
+use crate::authentication::login::{LoginChallenge, LoginFinish, LoginStart, Mac, ServerLogin};
+use crate::authentication::token::{SessionTable, SessionToken};
 use crate::authentication::user::User;
-use crate::cryptography::cryptography::hash_password;
+use crate::client::credentials::CredentialUpdate;
+use crate::client::registration::Registration;
+use crate::cryptography::{KdfParams, PaddingPolicy};
+use crate::error::SafeStoreError;
+use crate::sharing::envelope::KeyEnvelope;
+use crate::sharing::registry::{Capability, Share, ShareRegistry, ShareStatus};
 
 use argon2::password_hash::SaltString;
-use dryoc::types::ByteArray;
+use dryoc::classic::crypto_box::{crypto_box_keypair, PublicKey, SecretKey};
+use dryoc::classic::crypto_core::crypto_scalarmult_base;
+use dryoc::classic::crypto_generichash::crypto_generichash;
 use uuid::Uuid;
 
 #[derive(Debug)]
@@ -15,94 +29,614 @@ pub struct Server {
     // there are no files in the root folder
     pub root_folders: Vec<Folder>,
     pub enc_master_keys: Vec<(Vec<u8>, Vec<u8>)>, // (user_id (also the name of the root folder for that user), enc_master_key)
-    // This contains the user, the password salt, a salt value that needs to be used to create the challenge hash which will be used to authenticate the user
-    pub users: Vec<(User, SaltString, SaltString, Vec<u8>)>,
+    // This contains the user, the password salt, the Argon2 parameters the password was hashed with
+    // and the public login key derived from the password hash, see authentication::login
+    pub users: Vec<(User, SaltString, KdfParams, PublicKey)>,
+    // Minimum Argon2 parameters for new passwords, users hashed with weaker ones are upgraded on their next login
+    pub kdf_policy: KdfParams,
+    // How clients pad names and contents before encrypting them, changing it only affects what is encrypted afterwards
+    pub padding: PaddingPolicy,
+    // Random secret from which the fake salts and the dummy login keys of unknown users are derived
+    dummy_secret: [u8; 32],
+    // Static key of the server in the login protocol, clients have to know the public half beforehand
+    keypair: (PublicKey, SecretKey),
+    // Logins between login_start and login_finish, not persisted
+    pending_logins: BTreeMap<Uuid, PendingLogin>,
+    // Failed login attempts, persisted with the users so that restarting the server does not lift a lockout
+    pub limiter: LoginLimiter,
+    // Sessions opened by login_finish, not persisted so a restarted server has no open sessions
+    pub sessions: SessionTable,
+    // Folder keys users offered to each other, persisted
+    pub shares: ShareRegistry,
+    clock: Box<dyn Clock>,
+}
+
+// What a successful login hands back to the client
+#[derive(Debug)]
+pub struct LoginResponse {
+    pub enc_root_folder: Folder,
+    pub enc_master_key: Vec<u8>,
+    // Lets the client check that it talked to the server it expected
+    pub server_mac: Mac,
+    // Set when the password was hashed with weaker parameters than the policy, the client should change it to the same password with these
+    pub kdf_upgrade: Option<KdfParams>,
+    // Authenticates every further call of this session
+    pub token: SessionToken,
 }
 
+struct PendingLogin {
+    username: Vec<u8>,
+    client: Vec<u8>,
+    user_id: Option<Uuid>,
+    started: u64,
+    login: ServerLogin,
+}
+
+impl std::fmt::Debug for PendingLogin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PendingLogin").field("username", &self.username).field("started", &self.started).finish_non_exhaustive()
+    }
+}
+
+// On-disk format: MAGIC, FORMAT_VERSION (u32) and then the server key pair, password hashing policy, padding policy, users, login limiter, root folders, encrypted master keys and shares
+const MAGIC: &[u8; 9] = b"SAFESTORE";
+const FORMAT_VERSION: u32 = 1;
+// Seconds a client has between login_start and login_finish
+const LOGIN_TIMEOUT: u64 = 60;
+// Logins a client can have between login_start and login_finish at the same time
+const MAX_PENDING_LOGINS: usize = 5;
+
 impl Server {
     pub fn new() -> Server {
         Server {
             root_folders: Vec::new(),
             enc_master_keys: Vec::new(),
             users: Vec::new(),
+            kdf_policy: KdfParams::default(),
+            padding: PaddingPolicy::default(),
+            dummy_secret: rand::random(),
+            keypair: crypto_box_keypair(),
+            pending_logins: BTreeMap::new(),
+            limiter: LoginLimiter::default(),
+            sessions: SessionTable::default(),
+            shares: ShareRegistry::default(),
+            clock: Box::new(SystemClock),
         }
     }
 
-    pub fn get_password_salt(&self, username: Vec<u8>) -> Option<SaltString> {
-        let user_id = self.get_uid_from_name(&username);
-        self.users.iter().find(|(u, _, _, _)| u.id == user_id.unwrap()).map(|(_, salt, _, _)| salt.clone())
+    pub fn with_policy(policy: LimiterPolicy) -> Server {
+        let mut server = Server::new();
+        server.limiter.policy = policy;
+        server
+    }
+
+    // The clock is not persisted, a loaded server always starts with the system clock
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
     }
 
-    pub fn login(&self, username: &Vec<u8>, given_hash: Vec<u8>) -> (Folder, Vec<u8>) {
-        // Preventing timing attacks
-        let mut _valid = false;
-        let user_id = self.get_uid_from_name(username);
-        
-        _valid = self.users.iter().any(|(u, _, _, _)| u.id == user_id.unwrap());
-        _valid = self.users.iter().any(|(u, _, _, challenge_hash)| {
-            if u.id == user_id.unwrap() {
-                return given_hash == challenge_hash.clone();
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.0
+    }
+
+    // Unknown usernames get stable fake salts and the policy parameters, so the answers do not reveal which accounts exist
+    pub fn get_password_salt(&self, username: Vec<u8>) -> Result<(SaltString, KdfParams), SafeStoreError> {
+        match self.users.iter().find(|(u, _, _, _)| u.name == username) {
+            Some((_, password_salt, kdf_params, _)) => Ok((password_salt.clone(), *kdf_params)),
+            None => {
+                let fake_salt = self.dummy_bytes::<16>(b"password salt", &username)?;
+                let fake_salt = SaltString::encode_b64(&fake_salt).map_err(|err| SafeStoreError::Malformed(err.to_string()))?;
+                Ok((fake_salt, self.kdf_policy))
             }
-            return false;
+        }
+    }
+
+    // client identifies where the attempt comes from (e.g. an IP address), failures are counted per username and per client
+    pub fn login_start(&mut self, client: &[u8], username: &[u8], start: LoginStart) -> Result<LoginChallenge, SafeStoreError> {
+        let now = self.clock.now();
+        if let Err(err) = self.limiter.check(username, client, now) {
+            println!("[SERVER] User login refused, too many failed attempts");
+            return Err(err);
+        }
+        self.pending_logins.retain(|_, pending| now.saturating_sub(pending.started) < LOGIN_TIMEOUT);
+        // A client starting more logins drops its oldest one. The cap is not per username, or anyone could keep a user from logging in.
+        let by_client: Vec<(u64, Uuid)> = self.pending_logins.iter()
+            .filter(|(_, pending)| pending.client == client)
+            .map(|(login_id, pending)| (pending.started, *login_id))
+            .collect();
+        if by_client.len() >= MAX_PENDING_LOGINS {
+            if let Some((_, oldest)) = by_client.into_iter().min() {
+                self.pending_logins.remove(&oldest);
+            }
+        }
+
+        // Unknown users run the protocol against a dummy login key, and fail in login_finish like a wrong password
+        let record = self.users.iter().find(|(u, _, _, _)| u.name == username);
+        let (user_id, client_pk) = match record {
+            Some((user, _, _, login_key)) => (Some(user.id), *login_key),
+            None => (None, self.dummy_login_key(username)?),
+        };
+        let (login, challenge) = ServerLogin::respond(&self.keypair, &client_pk, username, &start)?;
+        self.pending_logins.insert(challenge.login_id, PendingLogin {
+            username: username.to_vec(),
+            client: client.to_vec(),
+            user_id,
+            started: now,
+            login,
         });
-        if _valid {
-            println!("[SERVER] User login successful");
-            // folder names are id of user whos folder it is
-            let mut enc_master_key = Vec::new();
-            for (folder_name, key) in &self.enc_master_keys {
-                if Uuid::from_bytes(*folder_name.as_array()) == user_id.unwrap() {
-                    enc_master_key = key.to_vec();
-                    break;
+        Ok(challenge)
+    }
+
+    pub fn login_finish(&mut self, finish: LoginFinish) -> Result<LoginResponse, SafeStoreError> {
+        let now = self.clock.now();
+        // A pending login can only be finished once
+        let pending = match self.pending_logins.remove(&finish.login_id) {
+            Some(pending) if now.saturating_sub(pending.started) < LOGIN_TIMEOUT => pending,
+            _ => {
+                println!("[SERVER] User login failed, unknown or expired login");
+                return Err(SafeStoreError::AuthenticationFailed);
+            }
+        };
+        // Failures recorded since login_start count too
+        if let Err(err) = self.limiter.check(&pending.username, &pending.client, now) {
+            println!("[SERVER] User login refused, too many failed attempts");
+            return Err(err);
+        }
+
+        let server_mac = pending.login.finish(&finish);
+        let user_id = match (server_mac.is_ok(), pending.user_id) {
+            (true, Some(user_id)) => user_id,
+            _ => {
+                // The wrong password was provided, or the user does not exist
+                println!("[SERVER] User login failed");
+                self.limiter.record_failure(&pending.username, &pending.client, now);
+                return Err(SafeStoreError::AuthenticationFailed);
+            }
+        };
+        println!("[SERVER] User login successful");
+        self.limiter.record_success(&pending.username);
+        // folder names are id of user whos folder it is
+        let enc_master_key = self.enc_master_keys.iter()
+            .find(|(folder_name, _)| folder_name == user_id.as_bytes())
+            .map(|(_, key)| key.clone())
+            .ok_or(SafeStoreError::MissingKey)?;
+        let enc_root_folder = self.root_folders.iter()
+            .find(|folder| folder.name == user_id.as_bytes())
+            .ok_or(SafeStoreError::UnknownUser)?
+            .duplicate();
+        let kdf_upgrade = self.users.iter()
+            .find(|(u, _, _, _)| u.id == user_id)
+            .map(|(_, _, kdf_params, _)| *kdf_params)
+            .filter(|kdf_params| kdf_params.is_weaker_than(&self.kdf_policy))
+            .map(|kdf_params| kdf_params.strongest(&self.kdf_policy));
+        Ok(LoginResponse {
+            enc_root_folder,
+            enc_master_key,
+            server_mac: server_mac?,
+            kdf_upgrade,
+            token: self.sessions.create(user_id, now)?,
+        })
+    }
+
+    // Uploads the tree and ends the session, the token is revoked afterwards.
+    // etag is the one of the stored tree the upload was made from, see Folder::etag
+    pub fn logout(&mut self, token: &SessionToken, enc_root_folder: Folder, etag: [u8; 32]) -> Result<(), SafeStoreError> {
+        let user_id = match self.sessions.authenticate(token, self.clock.now()) {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                println!("[SERVER] User logout failed, invalid session");
+                return Err(err);
+            }
+        };
+        // The session stays open if the tree is rejected, so that the client can upload it again
+        if let Err(err) = self.replace_root_folder(user_id, enc_root_folder, etag) {
+            println!("[SERVER] User logout failed, root folder rejected");
+            return Err(err);
+        }
+        self.sessions.revoke(token)?;
+        println!("[SERVER] User logout successful");
+        Ok(())
+    }
+
+    // Offers the folder described by envelope to the user recipient. The folder has to be in the caller's own tree,
+    // or shared with the caller with Reshare, who can then pass on at most their own capability.
+    pub fn create_share(&mut self, token: &SessionToken, recipient: Uuid, envelope: KeyEnvelope, capability: Capability) -> Result<Uuid, SafeStoreError> {
+        let sender = self.sessions.authenticate(token, self.clock.now())?;
+        if !self.users.iter().any(|(u, _, _, _)| u.id == recipient) {
+            return Err(SafeStoreError::UnknownUser);
+        }
+        self.check_sealed_by(sender, &envelope)?;
+        let owner = self.folder_owner(envelope.folder_id).ok_or_else(|| SafeStoreError::NotFound(envelope.folder_id.to_string()))?;
+        if owner != sender {
+            match self.shares.capability(sender, envelope.folder_id) {
+                None => return Err(SafeStoreError::NotFound(envelope.folder_id.to_string())),
+                Some(own) if own < Capability::Reshare || capability > own => return Err(SafeStoreError::PermissionDenied),
+                Some(_) => {}
+            }
+        }
+        let share_id = self.shares.insert(owner, sender, recipient, envelope, capability);
+        println!("[SERVER] Share created");
+        Ok(share_id)
+    }
+
+    // Shares the caller created, whatever their status
+    pub fn list_outgoing_shares(&mut self, token: &SessionToken) -> Result<Vec<Share>, SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        Ok(self.shares.outgoing(user_id).cloned().collect())
+    }
+
+    // Takes the folder back from recipient, who loses access to it on the server right away. Allowed to the owner and to Admin recipients.
+    // The shares recipient passed on are dropped with it. Keys recipient already opened still decrypt what was stored before,
+    // so the folder is marked for rotation: recipients cannot save it until the owner's client has given it new keys, see rekey.
+    pub fn revoke_share(&mut self, token: &SessionToken, folder_id: Uuid, recipient: Uuid) -> Result<(), SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        if !self.owns_folder(user_id, folder_id) {
+            match self.shares.capability(user_id, folder_id) {
+                None => return Err(SafeStoreError::NotFound(folder_id.to_string())),
+                Some(capability) if capability < Capability::Admin => return Err(SafeStoreError::PermissionDenied),
+                Some(_) => {}
+            }
+        }
+        let owner = self.folder_owner(folder_id).ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?;
+        if self.shares.revoke(folder_id, recipient) == 0 {
+            return Err(SafeStoreError::NotFound(folder_id.to_string()));
+        }
+        let reshares = self.shares.revoke_orphaned_reshares(folder_id, owner);
+        self.shares.mark_for_rotation(folder_id, owner);
+        println!("[SERVER] Share revoked with {} reshares, folder keys to be rotated", reshares);
+        Ok(())
+    }
+
+    // Every share of folder_id and of the folders below it, reshares included, for the owner of the folder only
+    pub fn list_folder_shares(&mut self, token: &SessionToken, folder_id: Uuid) -> Result<Vec<Share>, SafeStoreError> {
+        let owner = self.sessions.authenticate(token, self.clock.now())?;
+        let folder_ids = self.owned_subtree(owner, folder_id).ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))?;
+        Ok(self.shares.iter().filter(|share| folder_ids.contains(&share.envelope.folder_id)).cloned().collect())
+    }
+
+    // Folders of the caller that had a share revoked and still have their old keys
+    pub fn list_pending_rotations(&mut self, token: &SessionToken) -> Result<Vec<Uuid>, SafeStoreError> {
+        let owner = self.sessions.authenticate(token, self.clock.now())?;
+        Ok(self.shares.pending_rotations(owner))
+    }
+
+    // Stores the owner's tree with new keys for the rotated folders and everything below them, along with the envelopes
+    // sealed again under them for every share of those folders, see ClientSession::revoke_share. Rotated folders the tree
+    // no longer holds need no envelopes. Everything is checked before anything is changed, so a rejected call leaves the
+    // shares, the tree and the pending rotations as they were.
+    pub fn rekey(&mut self, token: &SessionToken, rotated: Vec<Uuid>, envelopes: Vec<(Uuid, KeyEnvelope)>, enc_root_folder: Folder, etag: [u8; 32]) -> Result<(), SafeStoreError> {
+        let owner = self.sessions.authenticate(token, self.clock.now())?;
+        let pending = self.shares.pending_rotations(owner);
+        let mut folder_ids = BTreeSet::new();
+        for folder_id in &rotated {
+            if !pending.contains(folder_id) {
+                return Err(SafeStoreError::Malformed("folder is not waiting for new keys".to_string()));
+            }
+            folder_ids.extend(self.owner_folder(owner, *folder_id).map(Folder::folder_ids).unwrap_or_default());
+        }
+
+        // Every share below the rotated folders needs exactly one new envelope, for the same folder
+        let remaining: BTreeSet<Uuid> = self.shares.iter()
+            .filter(|share| folder_ids.contains(&share.envelope.folder_id))
+            .map(|share| share.id)
+            .collect();
+        let mut rekeyed = BTreeSet::new();
+        for (share_id, envelope) in &envelopes {
+            self.check_sealed_by(owner, envelope)?;
+            let share = self.shares.get_by_id(*share_id)?;
+            if !remaining.contains(share_id) || share.envelope.folder_id != envelope.folder_id || !rekeyed.insert(*share_id) {
+                return Err(SafeStoreError::Malformed("envelope does not match a share of the folder".to_string()));
+            }
+        }
+        if rekeyed != remaining {
+            return Err(SafeStoreError::Malformed("every share of the folder needs a new envelope".to_string()));
+        }
+        let root_index = self.check_root_folder(owner, &enc_root_folder, etag)?;
+
+        for (share_id, envelope) in envelopes {
+            // The share keeps its status and capability, the owner becomes its sender
+            let share = self.shares.get_by_id_mut(share_id)?;
+            share.sender = owner;
+            share.envelope = envelope;
+        }
+        for folder_id in rotated {
+            self.shares.clear_rotation(folder_id);
+        }
+        self.root_folders[root_index] = enc_root_folder;
+        println!("[SERVER] Folder keys rotated");
+        Ok(())
+    }
+
+    // Replaces the caller's stored root folder without closing the session
+    pub fn put_root_folder(&mut self, token: &SessionToken, enc_root_folder: Folder, etag: [u8; 32]) -> Result<(), SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        self.replace_root_folder(user_id, enc_root_folder, etag)
+    }
+
+    // The caller's tree as stored, to start again from when an upload was rejected with Conflict
+    pub fn get_root_folder(&mut self, token: &SessionToken) -> Result<Folder, SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        self.root_folders.iter()
+            .find(|folder| folder.name == user_id.as_bytes())
+            .map(Folder::duplicate)
+            .ok_or(SafeStoreError::UnknownUser)
+    }
+
+    // Pending and accepted shares addressed to the caller
+    pub fn list_incoming_shares(&mut self, token: &SessionToken) -> Result<Vec<Share>, SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        Ok(self.shares.incoming(user_id).cloned().collect())
+    }
+
+    // From then on the caller can use the shared folder as far as the share's capability allows
+    pub fn accept_share(&mut self, token: &SessionToken, share_id: Uuid) -> Result<KeyEnvelope, SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        let share = self.shares.get_mut(share_id, user_id)?;
+        share.status = ShareStatus::Accepted;
+        println!("[SERVER] Share accepted");
+        Ok(share.envelope.clone())
+    }
+
+    // Back to Pending, for a client that accepted a share but could not open or mount the folder
+    pub fn unaccept_share(&mut self, token: &SessionToken, share_id: Uuid) -> Result<(), SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        self.shares.get_mut(share_id, user_id)?.status = ShareStatus::Pending;
+        println!("[SERVER] Share back to pending");
+        Ok(())
+    }
+
+    pub fn decline_share(&mut self, token: &SessionToken, share_id: Uuid) -> Result<(), SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        self.shares.remove(share_id, user_id)?;
+        println!("[SERVER] Share declined");
+        Ok(())
+    }
+
+    // The encrypted folder with this id, for its owner and for users who accepted a share of it.
+    // Root folders are not handed out, the key of a shared folder comes from a KeyEnvelope.
+    pub fn get_folder(&mut self, token: &SessionToken, folder_id: Uuid) -> Result<Folder, SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        if !self.can_access(user_id, folder_id) {
+            return Err(SafeStoreError::NotFound(folder_id.to_string()));
+        }
+        self.folder_owner(folder_id)
+            .and_then(|owner| self.owner_folder(owner, folder_id))
+            .map(Folder::duplicate)
+            .ok_or_else(|| SafeStoreError::NotFound(folder_id.to_string()))
+    }
+
+    // Replaces the stored folder that has the same id as enc_folder, for its owner and recipients with Write.
+    // Like for root folders, etag has to be the one of the stored folder, otherwise someone else changed it in the meantime.
+    // As the owner's tree holds the folder, its etag changes too and the owner's session has to fetch it again before uploading, see ClientSession::refresh.
+    pub fn put_folder(&mut self, token: &SessionToken, enc_folder: Folder, etag: [u8; 32]) -> Result<(), SafeStoreError> {
+        let user_id = self.sessions.authenticate(token, self.clock.now())?;
+        let is_owner = self.owns_folder(user_id, enc_folder.id);
+        if !is_owner {
+            match self.shares.capability(user_id, enc_folder.id) {
+                None => return Err(SafeStoreError::NotFound(enc_folder.id.to_string())),
+                Some(Capability::Read) => {
+                    println!("[SERVER] Shared folder update rejected, read only");
+                    return Err(SafeStoreError::PermissionDenied);
                 }
+                Some(_) => {}
             }
-            let root_folder: &Folder = self.root_folders.iter().find(|folder| folder.name == user_id.unwrap().as_bytes()).unwrap();
-            return (root_folder.clone(), enc_master_key.clone());
-        } else {
-            // The wrong password was provided
-            panic!("[SERVER] User login failed");
-        }
-    }
-
-    pub fn logout(&mut self, username: Vec<u8>, enc_root_folder: Folder, enc_master_key: Vec<u8>, given_hash: Vec<u8>, new_challenge_hash: Option<Vec<u8>>, new_password_salt: Option<SaltString>) {
-        let user_id = self.get_uid_from_name(&username).unwrap();
-        let mut _valid = self.users.iter().any(|(u, _, _, _)| u.id == user_id);
-        let mut _password_change = false;
-        _valid = self.users.iter().any(|(u, _, challenge_salt, challenge_hash)| {
-            if u.id == user_id {
-                let (hash, _) = hash_password(given_hash.clone(), Some(challenge_salt));
-                return hash == challenge_hash.clone();
+        }
+        let owner = self.folder_owner(enc_folder.id).ok_or_else(|| SafeStoreError::NotFound(enc_folder.id.to_string()))?;
+        // Saved under keys a revoked user may still have, the owner's client has to rotate them first
+        if self.rotation_pending(owner, enc_folder.id) {
+            println!("[SERVER] Shared folder update rejected, keys not rotated yet");
+            return Err(SafeStoreError::Conflict);
+        }
+        self.check_folder_ids(owner, &enc_folder, enc_folder.id)?;
+        let folder = self.root_folders.iter_mut()
+            .find(|root| root.name == owner.as_bytes())
+            .and_then(|root| root.folders.iter_mut().find_map(|folder| folder.find_mut(enc_folder.id)))
+            .ok_or_else(|| SafeStoreError::NotFound(enc_folder.id.to_string()))?;
+        if folder.etag()? != etag {
+            println!("[SERVER] Shared folder update rejected, changed since it was fetched");
+            return Err(SafeStoreError::Conflict);
+        }
+        // Writers of a folder get the write keys of the shared folders inside it, those still only change for their own writers
+        if !is_owner {
+            for nested_id in folder.shared_folder_ids() {
+                if self.shares.capability(user_id, nested_id) >= Some(Capability::Write) {
+                    continue;
+                }
+                let stored = folder.find(nested_id).map(Folder::etag).transpose()?;
+                let uploaded = enc_folder.find(nested_id).map(Folder::etag).transpose()?;
+                if stored.is_some() && stored != uploaded {
+                    println!("[SERVER] Shared folder update rejected, it changes a shared folder inside it");
+                    return Err(SafeStoreError::PermissionDenied);
+                }
             }
-            return false;
-        });
-        if _valid {
-            if !(new_challenge_hash.is_none() && new_password_salt.is_none()) {
-                self.users.iter_mut().find(|(u, _, _, _)| u.id == user_id).map(|(_, password_salt, _, hash)| {
-                    *password_salt = new_password_salt.clone().unwrap();
-                    *hash = new_challenge_hash.clone().unwrap();
-
-                });
-                _password_change = true;
-            } 
-            self.root_folders.iter_mut().find(|folder| folder.name == user_id.as_bytes()).map(|folder| {
-                *folder = enc_root_folder.clone();
-            });
-            self.enc_master_keys.iter_mut().find(|(name, _)| Uuid::from_bytes(*name.as_array()) == user_id).map(|(_, key)| {
-                *key = enc_master_key.clone();
-            });
-            if _password_change {
-                println!("[SERVER] User logout successful, password changed");
-            } else {
-                println!("[SERVER] User logout successful");
+        }
+        *folder = enc_folder;
+        println!("[SERVER] Shared folder updated");
+        Ok(())
+    }
+
+    // Needs an open session and a fresh proof of the old password, i.e. a login started with login_start.
+    // Returns the MAC with which the client authenticates the server, like login_finish.
+    pub fn change_password(&mut self, token: &SessionToken, proof: LoginFinish, update: CredentialUpdate) -> Result<Mac, SafeStoreError> {
+        let (user_id, server_mac) = self.replace_credentials(token, proof, update, false)?;
+        // Sessions opened with the old password are closed, except the one that changed it
+        self.sessions.revoke_others(user_id, token)?;
+        println!("[SERVER] Password changed");
+        Ok(server_mac)
+    }
+
+    // Answers LoginResponse::kdf_upgrade: the password is hashed again with exactly the parameters offered at login.
+    // Unlike change_password the user's other sessions are kept, it is the same password under stronger parameters.
+    pub fn rehash_password(&mut self, token: &SessionToken, proof: LoginFinish, update: CredentialUpdate) -> Result<Mac, SafeStoreError> {
+        let (_, server_mac) = self.replace_credentials(token, proof, update, true)?;
+        println!("[SERVER] Password rehashed");
+        Ok(server_mac)
+    }
+
+    fn replace_credentials(&mut self, token: &SessionToken, proof: LoginFinish, update: CredentialUpdate, rehash: bool) -> Result<(Uuid, Mac), SafeStoreError> {
+        let now = self.clock.now();
+        let CredentialUpdate { password_salt, kdf_params, login_key, enc_master_key } = update;
+        let (new_password_salt, new_kdf_params, new_login_key, new_enc_master_key) = match (password_salt, kdf_params, login_key, enc_master_key) {
+            (Some(password_salt), Some(kdf_params), Some(login_key), Some(enc_master_key)) => (password_salt, kdf_params, login_key, enc_master_key),
+            (password_salt, kdf_params, login_key, _) => {
+                let missing = if password_salt.is_none() {
+                    "password salt"
+                } else if kdf_params.is_none() {
+                    "password hashing parameters"
+                } else if login_key.is_none() {
+                    "login key"
+                } else {
+                    "encrypted master key"
+                };
+                println!("[SERVER] Password change rejected, partial update");
+                return Err(SafeStoreError::PartialCredentialUpdate(missing.to_string()));
             }
-        } else {
-            // The wrong password was provided
-            panic!("[SERVER] User logout failed");
+        };
+        self.check_kdf_params(&new_kdf_params)?;
+
+        let user_id = self.sessions.authenticate(token, now)?;
+        let pending = match self.pending_logins.remove(&proof.login_id) {
+            Some(pending) if now.saturating_sub(pending.started) < LOGIN_TIMEOUT => pending,
+            _ => return Err(SafeStoreError::AuthenticationFailed),
+        };
+        if let Err(err) = self.limiter.check(&pending.username, &pending.client, now) {
+            println!("[SERVER] Password change refused, too many failed attempts");
+            return Err(err);
+        }
+        let server_mac = match pending.login.finish(&proof) {
+            Ok(server_mac) if pending.user_id == Some(user_id) => server_mac,
+            _ => {
+                println!("[SERVER] Password change failed, wrong password");
+                self.limiter.record_failure(&pending.username, &pending.client, now);
+                return Err(SafeStoreError::AuthenticationFailed);
+            }
+        };
+        self.limiter.record_success(&pending.username);
+
+        // Look everything up first so that either all three parts are replaced or none
+        let user_index = self.users.iter().position(|(u, _, _, _)| u.id == user_id).ok_or(SafeStoreError::UnknownUser)?;
+        let key_index = self.enc_master_keys.iter().position(|(name, _)| name == user_id.as_bytes()).ok_or(SafeStoreError::MissingKey)?;
+        let (_, password_salt, kdf_params, login_key) = &mut self.users[user_index];
+        if rehash && (!kdf_params.is_weaker_than(&self.kdf_policy) || new_kdf_params != kdf_params.strongest(&self.kdf_policy)) {
+            println!("[SERVER] Password rehash rejected, no upgrade was offered");
+            return Err(SafeStoreError::Malformed("password hashing parameters do not match the upgrade".to_string()));
+        }
+        *password_salt = new_password_salt;
+        *kdf_params = new_kdf_params;
+        *login_key = new_login_key;
+        self.enc_master_keys[key_index].1 = new_enc_master_key;
+        Ok((user_id, server_mac))
+    }
+
+    pub fn register(&mut self, registration: Registration) -> Result<(), SafeStoreError> {
+        let Registration { user, enc_master_key, password_salt, kdf_params, login_key, enc_root_folder } = registration;
+        if self.get_uid_from_name(&user.name).is_some() {
+            println!("[SERVER] User registration failed, username already taken");
+            return Err(SafeStoreError::UsernameTaken);
+        }
+        if self.users.iter().any(|(u, _, _, _)| u.id == user.id) {
+            return Err(SafeStoreError::Malformed("user id already in use".to_string()));
+        }
+        if enc_root_folder.name != user.id.as_bytes() {
+            return Err(SafeStoreError::Malformed("root folder must be named after the user id".to_string()));
+        }
+        // The server never takes a user's secret keys in clear
+        if user.enc_keys.is_empty() {
+            return Err(SafeStoreError::Malformed("user keys must be wrapped".to_string()));
+        }
+        self.check_kdf_params(&kdf_params)?;
+        self.check_folder_ids(user.id, &enc_root_folder, enc_root_folder.id)?;
+        println!("[SERVER] User registration successful");
+        self.add_root_folder(enc_root_folder, enc_master_key);
+        self.users.push((user, password_salt, kdf_params, login_key));
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SafeStoreError> {
+        // Write to a temporary file first so a crash never leaves a half written store behind
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, self.serialize())?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Server, SafeStoreError> {
+        let bytes = fs::read(path)?;
+        Server::deserialize(&bytes)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_raw(MAGIC);
+        writer.write_u32(FORMAT_VERSION);
+        writer.write_bytes(&self.dummy_secret);
+        writer.write_bytes(&self.keypair.0);
+        writer.write_bytes(&self.keypair.1);
+        Server::encode_kdf_params(&mut writer, &self.kdf_policy);
+        writer.write_u32(self.padding.id());
+
+        writer.write_u64(self.users.len() as u64);
+        for (user, password_salt, kdf_params, login_key) in &self.users {
+            user.encode(&mut writer);
+            writer.write_bytes(password_salt.as_str().as_bytes());
+            Server::encode_kdf_params(&mut writer, kdf_params);
+            writer.write_bytes(login_key);
         }
+        self.limiter.encode(&mut writer);
+
+        writer.write_u64(self.root_folders.len() as u64);
+        for folder in &self.root_folders {
+            folder.encode(&mut writer);
+        }
+
+        writer.write_u64(self.enc_master_keys.len() as u64);
+        for (name, key) in &self.enc_master_keys {
+            writer.write_bytes(name);
+            writer.write_bytes(key);
+        }
+        self.shares.encode(&mut writer);
+
+        writer.into_bytes()
     }
 
-    pub fn add_user(&mut self, user: User, enc_master_key: Vec<u8>, password_salt: SaltString, challenge_salt: SaltString, challenge_hash: Vec<u8>, root_folder: Folder) {
-        self.add_root_folder(root_folder, enc_master_key);
-        self.users.push((user, password_salt, challenge_salt, challenge_hash));
+    pub fn deserialize(bytes: &[u8]) -> Result<Server, SafeStoreError> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_raw(MAGIC.len())? != MAGIC {
+            return Err(SafeStoreError::Malformed("not a SafeStore file".to_string()));
+        }
+        let version = reader.read_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(SafeStoreError::Malformed(format!("unsupported format version {}", version)));
+        }
+
+        let mut server = Server::new();
+        server.dummy_secret = reader.read_array()?;
+        server.keypair = (reader.read_array()?, reader.read_array()?);
+        server.kdf_policy = Server::decode_kdf_params(&mut reader)?;
+        server.padding = PaddingPolicy::from_id(reader.read_u32()?)?;
+        for _ in 0..reader.read_u64()? {
+            let user = User::decode(&mut reader)?;
+            let password_salt = Server::decode_salt(&mut reader)?;
+            let kdf_params = Server::decode_kdf_params(&mut reader)?;
+            let login_key = reader.read_array()?;
+            server.users.push((user, password_salt, kdf_params, login_key));
+        }
+        server.limiter = LoginLimiter::decode(&mut reader)?;
+
+        for _ in 0..reader.read_u64()? {
+            server.root_folders.push(Folder::decode(&mut reader)?);
+        }
+
+        for _ in 0..reader.read_u64()? {
+            server.enc_master_keys.push((reader.read_bytes()?, reader.read_bytes()?));
+        }
+        server.shares = ShareRegistry::decode(&mut reader)?;
+
+        if !reader.is_empty() {
+            return Err(SafeStoreError::Malformed("trailing data".to_string()));
+        }
+        Ok(server)
     }
 
     pub fn display_users(&self) {
@@ -118,9 +652,104 @@ impl Server {
         }
     }
 
-    pub fn get_user(&self, name: &Vec<u8>) -> Option<&User> {
-        let uid = self.get_uid_from_name(name);
-        self.users.iter().find(|(u, _, _, _)| u.id == uid.unwrap()).map(|(u, _, _, _)| u)
+    pub fn get_user(&self, name: &[u8]) -> Result<&User, SafeStoreError> {
+        self.users.iter().find(|(u, _, _, _)| u.name == name).map(|(u, _, _, _)| u).ok_or(SafeStoreError::UnknownUser)
+    }
+
+    pub fn get_user_by_id(&self, id: Uuid) -> Result<&User, SafeStoreError> {
+        self.users.iter().find(|(u, _, _, _)| u.id == id).map(|(u, _, _, _)| u).ok_or(SafeStoreError::UnknownUser)
+    }
+
+    fn replace_root_folder(&mut self, user_id: Uuid, enc_root_folder: Folder, etag: [u8; 32]) -> Result<(), SafeStoreError> {
+        let index = self.check_root_folder(user_id, &enc_root_folder, etag)?;
+        self.root_folders[index] = enc_root_folder;
+        Ok(())
+    }
+
+    // Index of the stored root folder of user_id, if enc_root_folder can replace it
+    fn check_root_folder(&self, user_id: Uuid, enc_root_folder: &Folder, etag: [u8; 32]) -> Result<usize, SafeStoreError> {
+        if enc_root_folder.name != user_id.as_bytes() {
+            return Err(SafeStoreError::Malformed("root folder must be named after the user id".to_string()));
+        }
+        let index = self.root_folders.iter()
+            .position(|folder| folder.name == user_id.as_bytes())
+            .ok_or(SafeStoreError::UnknownUser)?;
+        if self.root_folders[index].etag()? != etag {
+            println!("[SERVER] Root folder update rejected, changed since it was fetched");
+            return Err(SafeStoreError::Conflict);
+        }
+        self.check_folder_ids(user_id, enc_root_folder, self.root_folders[index].id)?;
+        Ok(index)
+    }
+
+    // Folder ids are unique across all trees. Otherwise a user could put a folder with the id of someone else's
+    // shared folder into their own tree and pass for its owner. Only the ids of the folder being replaced can be reused.
+    fn check_folder_ids(&self, owner: Uuid, folder: &Folder, replaced: Uuid) -> Result<(), SafeStoreError> {
+        let replaced_ids: BTreeSet<Uuid> = self.root_folders.iter()
+            .find(|root| root.name == owner.as_bytes())
+            .and_then(|root| root.find(replaced))
+            .map(|folder| folder.folder_ids().into_iter().collect())
+            .unwrap_or_default();
+        let taken: BTreeSet<Uuid> = self.root_folders.iter()
+            .flat_map(Folder::folder_ids)
+            .filter(|id| !replaced_ids.contains(id))
+            .collect();
+        for id in folder.folder_ids() {
+            if taken.contains(&id) || self.shares.owner(id).is_some_and(|shared_by| shared_by != owner) {
+                println!("[SERVER] Folder rejected, id already in use");
+                return Err(SafeStoreError::Malformed(format!("folder id {} already in use", id)));
+            }
+        }
+        Ok(())
+    }
+
+    // The owner recorded with the shares of folder_id, or for a folder never shared the user whose tree holds it
+    fn folder_owner(&self, folder_id: Uuid) -> Option<Uuid> {
+        self.shares.owner(folder_id).or_else(|| {
+            self.root_folders.iter()
+                .find(|root| root.folders.iter().any(|folder| folder.find(folder_id).is_some()))
+                .and_then(|root| Uuid::from_slice(&root.name).ok())
+        })
+    }
+
+    // folder_id looked up below the root folder of owner only
+    fn owner_folder(&self, owner: Uuid, folder_id: Uuid) -> Option<&Folder> {
+        self.root_folders.iter()
+            .find(|root| root.name == owner.as_bytes())
+            .and_then(|root| root.folders.iter().find_map(|folder| folder.find(folder_id)))
+    }
+
+    // Ids of folder_id and of the folders below it, if user_id owns folder_id
+    fn owned_subtree(&self, user_id: Uuid, folder_id: Uuid) -> Option<Vec<Uuid>> {
+        if self.folder_owner(folder_id) != Some(user_id) {
+            return None;
+        }
+        self.owner_folder(user_id, folder_id).map(Folder::folder_ids)
+    }
+
+    // folder_id, a folder above it or one below it is waiting for new keys
+    fn rotation_pending(&self, owner: Uuid, folder_id: Uuid) -> bool {
+        let below = self.owner_folder(owner, folder_id).map(Folder::folder_ids).unwrap_or_default();
+        self.shares.pending_rotations(owner).into_iter().any(|pending| {
+            below.contains(&pending) || self.owner_folder(owner, pending).is_some_and(|folder| folder.find(folder_id).is_some())
+        })
+    }
+
+    fn owns_folder(&self, user_id: Uuid, folder_id: Uuid) -> bool {
+        self.owned_subtree(user_id, folder_id).is_some()
+    }
+
+    fn can_access(&self, user_id: Uuid, folder_id: Uuid) -> bool {
+        self.owns_folder(user_id, folder_id) || self.shares.capability(user_id, folder_id).is_some()
+    }
+
+    // The envelope names user_id as the user who sealed it
+    fn check_sealed_by(&self, user_id: Uuid, envelope: &KeyEnvelope) -> Result<(), SafeStoreError> {
+        let sender = self.users.iter().find(|(u, _, _, _)| u.id == user_id).map(|(u, _, _, _)| &u.name);
+        if sender != Some(&envelope.sender) {
+            return Err(SafeStoreError::Malformed("envelope not sealed by the share sender".to_string()));
+        }
+        Ok(())
     }
 
     fn add_root_folder(&mut self, folder: Folder, enc_master_key: Vec<u8>) {
@@ -129,8 +758,386 @@ impl Server {
         self.enc_master_keys.push((folder_name, enc_master_key));
     }
 
-    fn get_uid_from_name(&self, name: &Vec<u8>) -> Option<Uuid> {
-        let user = self.users.iter().find(|(u, _, _, _)| u.name == *name);
+    fn dummy_login_key(&self, username: &[u8]) -> Result<PublicKey, SafeStoreError> {
+        let mut login_key = PublicKey::default();
+        crypto_scalarmult_base(&mut login_key, &self.dummy_bytes::<32>(b"login key", username)?);
+        Ok(login_key)
+    }
+
+    // Deterministic per server and per username, but unpredictable without the server's dummy secret
+    fn dummy_bytes<const N: usize>(&self, label: &[u8], username: &[u8]) -> Result<[u8; N], SafeStoreError> {
+        let mut input = label.to_vec();
+        input.extend_from_slice(username);
+        let mut output = [0u8; N];
+        crypto_generichash(&mut output, &input, Some(&self.dummy_secret))
+            .map_err(|err| SafeStoreError::Malformed(err.to_string()))?;
+        Ok(output)
+    }
+
+    fn check_kdf_params(&self, kdf_params: &KdfParams) -> Result<(), SafeStoreError> {
+        if kdf_params.is_weaker_than(&self.kdf_policy) {
+            println!("[SERVER] Password hashing parameters below the policy");
+            return Err(SafeStoreError::Malformed("password hashing parameters below the server policy".to_string()));
+        }
+        Ok(())
+    }
+
+    fn encode_kdf_params(writer: &mut Writer, kdf_params: &KdfParams) {
+        writer.write_u32(kdf_params.m_cost);
+        writer.write_u32(kdf_params.t_cost);
+        writer.write_u32(kdf_params.p_cost);
+    }
+
+    fn decode_kdf_params(reader: &mut Reader) -> Result<KdfParams, SafeStoreError> {
+        Ok(KdfParams {
+            m_cost: reader.read_u32()?,
+            t_cost: reader.read_u32()?,
+            p_cost: reader.read_u32()?,
+        })
+    }
+
+    fn decode_salt(reader: &mut Reader) -> Result<SaltString, SafeStoreError> {
+        let salt = String::from_utf8(reader.read_bytes()?)
+            .map_err(|_| SafeStoreError::Malformed("invalid salt".to_string()))?;
+        SaltString::from_b64(&salt).map_err(|_| SafeStoreError::Malformed("invalid salt".to_string()))
+    }
+
+    fn get_uid_from_name(&self, name: &[u8]) -> Option<Uuid> {
+        let user = self.users.iter().find(|(u, _, _, _)| u.name == name);
         user.map(|(u, _, _, _)| u.id)
     }
-}
\ No newline at end of file
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::limiter::ManualClock;
+    use crate::authentication::login::ClientLogin;
+    use crate::client::credentials::derive_credentials;
+    use crate::client::registration::register;
+    use crate::client::session::ClientSession;
+    use crate::cryptography::{hash_password, MasterKey};
+    use crate::sharing::registry::Capability;
+
+    // Cheapest parameters Argon2 accepts, the tests are not about the password hashing cost
+    const TEST_KDF_PARAMS: KdfParams = KdfParams { m_cost: 8, t_cost: 1, p_cost: 1 };
+
+    fn server_with_alice() -> Server {
+        let mut server = Server::new();
+        server.kdf_policy = TEST_KDF_PARAMS;
+        server.register(register(b"Alice", b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        server
+    }
+
+    #[test]
+    fn unknown_user_and_wrong_password_fail_alike() {
+        let mut server = server_with_alice();
+        let unknown = ClientSession::open(&mut server, b"Mallory", b"password").err();
+        assert!(matches!(unknown, Some(SafeStoreError::AuthenticationFailed)), "{:?}", unknown);
+        let wrong = ClientSession::open(&mut server, b"Alice", b"wrong password").err();
+        assert!(matches!(wrong, Some(SafeStoreError::AuthenticationFailed)), "{:?}", wrong);
+        assert!(ClientSession::open(&mut server, b"Alice", b"password").is_ok());
+    }
+
+    // The client side of a login, up to the proof
+    fn prove_password(server: &mut Server, client: &[u8], username: &[u8], password: &[u8]) -> Result<LoginFinish, SafeStoreError> {
+        let (password_salt, kdf_params) = server.get_password_salt(username.to_vec())?;
+        let (password_hash, _) = hash_password(password, Some(&password_salt), &kdf_params)?;
+        let (mut login, start) = ClientLogin::start(username);
+        let challenge = server.login_start(client, username, start)?;
+        login.respond(&password_hash, &server.public_key(), &challenge)
+    }
+
+    #[test]
+    fn pending_logins_cannot_outrun_the_limiter() {
+        let mut server = server_with_alice();
+        server.set_clock(Box::new(ManualClock::new(1_000)));
+        let mut wrong = Vec::new();
+        for i in 0..4 {
+            wrong.push(prove_password(&mut server, format!("client {}", i).as_bytes(), b"Alice", b"wrong password").unwrap());
+        }
+        let right = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+
+        // Failures recorded after the login started still count
+        for finish in wrong {
+            assert!(matches!(server.login_finish(finish), Err(SafeStoreError::AuthenticationFailed)));
+        }
+        let finished = server.login_finish(right).err();
+        assert!(matches!(finished, Some(SafeStoreError::RateLimited { .. })), "{:?}", finished);
+    }
+
+    #[test]
+    fn pending_logins_of_other_clients_do_not_lock_the_user_out() {
+        let mut server = server_with_alice();
+        let clock = ManualClock::new(1_000);
+        server.set_clock(Box::new(clock.clone()));
+        let first = prove_password(&mut server, b"attacker", b"Alice", b"wrong password").unwrap();
+        for _ in 0..2 * MAX_PENDING_LOGINS {
+            clock.advance(1);
+            prove_password(&mut server, b"attacker", b"Alice", b"wrong password").unwrap();
+        }
+        // The attacker's oldest logins were dropped to make room for the new ones
+        assert_eq!(server.pending_logins.len(), MAX_PENDING_LOGINS);
+        assert!(!server.pending_logins.contains_key(&first.login_id));
+
+        let right = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        assert!(server.login_finish(right).is_ok());
+    }
+
+    #[test]
+    fn partial_credential_update_changes_nothing() {
+        let mut server = server_with_alice();
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let token = server.login_finish(finish).unwrap().token;
+        let alice = server.get_user(b"Alice").unwrap().id;
+        let stored = server.serialize();
+
+        for missing in 0..4 {
+            let mut update = derive_credentials(alice, b"new password", &MasterKey::generate().unwrap(), &TEST_KDF_PARAMS).unwrap();
+            match missing {
+                0 => update.password_salt = None,
+                1 => update.kdf_params = None,
+                2 => update.login_key = None,
+                _ => update.enc_master_key = None,
+            }
+            let proof = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+            let partial = server.change_password(&token, proof, update).err();
+            assert!(matches!(partial, Some(SafeStoreError::PartialCredentialUpdate(_))), "{:?}", partial);
+        }
+        assert_eq!(server.serialize(), stored);
+        assert!(ClientSession::open(&mut server, b"Alice", b"password").is_ok());
+    }
+
+    #[test]
+    fn weak_password_hash_is_upgraded_on_login() {
+        let mut server = server_with_alice();
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let other_session = server.login_finish(finish).unwrap().token;
+        let stronger = KdfParams { m_cost: 16, t_cost: 2, p_cost: 1 };
+        server.kdf_policy = stronger;
+
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        assert_eq!(server.login_finish(finish).unwrap().kdf_upgrade, Some(stronger));
+        ClientSession::open(&mut server, b"Alice", b"password").unwrap().close().unwrap();
+        assert_eq!(server.get_password_salt(b"Alice".to_vec()).unwrap().1, stronger);
+        // The same password was rehashed, the user's other sessions are still open
+        assert!(server.sessions.authenticate(&other_session, server.clock.now()).is_ok());
+
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let response = server.login_finish(finish).unwrap();
+        assert_eq!(response.kdf_upgrade, None);
+        // Without an upgrade on offer a rehash is refused
+        let alice = server.get_user(b"Alice").unwrap().id;
+        let update = derive_credentials(alice, b"new password", &MasterKey::generate().unwrap(), &stronger).unwrap();
+        let proof = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let refused = server.rehash_password(&response.token, proof, update).err();
+        assert!(matches!(refused, Some(SafeStoreError::Malformed(_))), "{:?}", refused);
+    }
+
+    #[test]
+    fn root_folder_must_belong_to_the_caller() {
+        let mut server = server_with_alice();
+        server.register(register(b"Bob", b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let token = server.login_finish(finish).unwrap().token;
+
+        let bob = server.get_user(b"Bob").unwrap().id;
+        let bob_root = server.root_folders.iter().find(|root| root.name == bob.as_bytes()).unwrap().duplicate();
+        let etag = bob_root.etag().unwrap();
+        assert!(matches!(server.put_root_folder(&token, bob_root.duplicate(), etag), Err(SafeStoreError::Malformed(_))));
+        assert!(matches!(server.logout(&token, bob_root, etag), Err(SafeStoreError::Malformed(_))));
+        // The rejected logout left the session open
+        assert!(server.sessions.authenticate(&token, server.clock.now()).is_ok());
+    }
+
+    #[test]
+    fn stale_root_folder_is_rejected() {
+        let mut server = server_with_alice();
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let response = server.login_finish(finish).unwrap();
+        let etag = response.enc_root_folder.etag().unwrap();
+
+        let mut changed = response.enc_root_folder.duplicate();
+        changed.signature = vec![1];
+        let changed_etag = changed.etag().unwrap();
+        server.put_root_folder(&response.token, changed, etag).unwrap();
+        // A second upload made from the version fetched at login would undo the first one
+        let stale = server.logout(&response.token, response.enc_root_folder.duplicate(), etag).err();
+        assert!(matches!(stale, Some(SafeStoreError::Conflict)), "{:?}", stale);
+        server.logout(&response.token, response.enc_root_folder, changed_etag).unwrap();
+    }
+
+    #[test]
+    fn envelopes_are_opened_on_the_recipient_client() {
+        let mut server = server_with_alice();
+        server.register(register(b"Bob", b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        let share_id = alice.share_folder("/home", b"Bob", Capability::Read).unwrap();
+        alice.close().unwrap();
+
+        // Neither the server nor what it stores holds a secret key
+        let stored = Server::deserialize(&server.serialize()).unwrap();
+        for (user, _, _, _) in server.users.iter().chain(&stored.users) {
+            assert!(!user.enc_keys.is_empty());
+        }
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        let envelope = bob.accept_share(share_id).unwrap();
+        assert!(bob.open_envelope(&envelope).unwrap().write_key.is_none());
+    }
+
+    #[test]
+    fn store_survives_save_and_load() {
+        let mut server = server_with_alice();
+        server.register(register(b"Bob", b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        alice.root_mut().write_file("/home/notes", b"kept".to_vec()).unwrap();
+        let share_id = alice.share_folder("/home", b"Bob", Capability::Write).unwrap();
+        alice.close().unwrap();
+
+        let path = std::env::temp_dir().join(format!("safestore-{}.bin", Uuid::new_v4()));
+        server.save(&path).unwrap();
+        let mut loaded = Server::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.serialize(), server.serialize());
+
+        let mut alice = ClientSession::open(&mut loaded, b"Alice", b"password").unwrap();
+        assert_eq!(alice.root_mut().read_file("/home/notes").unwrap(), b"kept");
+        alice.close().unwrap();
+        let mut bob = ClientSession::open(&mut loaded, b"Bob", b"password").unwrap();
+        let envelope = bob.accept_share(share_id).unwrap();
+        assert!(bob.open_shared(&envelope).is_ok());
+    }
+
+    #[test]
+    fn revocation_is_all_or_nothing() {
+        let mut server = server_with_alice();
+        for name in [&b"Bob"[..], b"Carol"] {
+            server.register(register(name, b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        }
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        alice.share_folder("/home", b"Bob", Capability::Read).unwrap();
+        let carol_share = alice.share_folder("/home", b"Carol", Capability::Read).unwrap();
+        alice.close().unwrap();
+
+        // Bob loses access right away, the keys are rotated by Alice's client on its next upload
+        let finish = prove_password(&mut server, b"client", b"Alice", b"password").unwrap();
+        let response = server.login_finish(finish).unwrap();
+        let home = response.enc_root_folder.folders[0].id;
+        let bob = server.get_user(b"Bob").unwrap().id;
+        let etag = response.enc_root_folder.etag().unwrap();
+        server.revoke_share(&response.token, home, bob).unwrap();
+        assert_eq!(server.list_pending_rotations(&response.token).unwrap(), vec![home]);
+        assert_eq!(server.list_folder_shares(&response.token, home).unwrap().len(), 1);
+
+        // Carol's share is not sealed again, so nothing is rotated
+        let partial = server.rekey(&response.token, vec![home], Vec::new(), response.enc_root_folder.duplicate(), etag).err();
+        assert!(matches!(partial, Some(SafeStoreError::Malformed(_))), "{:?}", partial);
+        assert_eq!(server.list_pending_rotations(&response.token).unwrap(), vec![home]);
+        server.logout(&response.token, response.enc_root_folder, etag).unwrap();
+
+        let sealed_key = server.shares.get_by_id(carol_share).unwrap().envelope.sealed_key.clone();
+        ClientSession::open(&mut server, b"Alice", b"password").unwrap().close().unwrap();
+        let alice = server.get_user(b"Alice").unwrap().id;
+        assert!(server.shares.pending_rotations(alice).is_empty());
+        assert_ne!(server.shares.get_by_id(carol_share).unwrap().envelope.sealed_key, sealed_key);
+        assert_eq!(server.shares.iter().map(|share| share.id).collect::<Vec<_>>(), vec![carol_share]);
+        let mut carol = ClientSession::open(&mut server, b"Carol", b"password").unwrap();
+        carol.accept_share(carol_share).unwrap();
+        assert!(carol.open_link("/shared/Alice/home").is_ok());
+    }
+
+    #[test]
+    fn admin_revocation_drops_reshares_and_waits_for_rotation() {
+        let mut server = server_with_alice();
+        for name in [&b"Bob"[..], b"Carol", b"Dave"] {
+            server.register(register(name, b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        }
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        let bob_share = alice.share_folder("/home", b"Bob", Capability::Admin).unwrap();
+        let carol_share = alice.share_folder("/home", b"Carol", Capability::Reshare).unwrap();
+        alice.close().unwrap();
+        let mut carol = ClientSession::open(&mut server, b"Carol", b"password").unwrap();
+        let envelope = carol.accept_share(carol_share).unwrap();
+        carol.reshare(&envelope, b"Dave", Capability::Read).unwrap();
+        carol.close().unwrap();
+
+        // Bob takes the folder back from Carol, which also takes it from Dave
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        let envelope = bob.accept_share(bob_share).unwrap();
+        bob.revoke_recipient(&envelope, b"Carol").unwrap();
+        // The folder is still under the keys Carol had, until Alice's client rotates them
+        let (envelope, home) = bob.open_link("/shared/Alice/home").unwrap();
+        let pending = bob.save_shared(&envelope, &home).err();
+        assert!(matches!(pending, Some(SafeStoreError::Conflict)), "{:?}", pending);
+        bob.close().unwrap();
+        assert_eq!(server.shares.iter().map(|share| share.id).collect::<Vec<_>>(), vec![bob_share]);
+
+        ClientSession::open(&mut server, b"Alice", b"password").unwrap().close().unwrap();
+        let alice = server.get_user(b"Alice").unwrap().id;
+        assert!(server.shares.pending_rotations(alice).is_empty());
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        let (envelope, home) = bob.open_link("/shared/Alice/home").unwrap();
+        bob.save_shared(&envelope, &home).unwrap();
+    }
+
+    #[test]
+    fn recipient_cannot_pass_for_the_owner() {
+        let mut server = server_with_alice();
+        for name in [&b"Bob"[..], b"Carol"] {
+            server.register(register(name, b"password", &TEST_KDF_PARAMS, server.padding).unwrap()).unwrap();
+        }
+        let mut alice = ClientSession::open(&mut server, b"Alice", b"password").unwrap();
+        alice.root_mut().create_dir_all("/home").unwrap();
+        let bob_share = alice.share_folder("/home", b"Bob", Capability::Read).unwrap();
+        alice.share_folder("/home", b"Carol", Capability::Read).unwrap();
+        alice.close().unwrap();
+        let mut bob = ClientSession::open(&mut server, b"Bob", b"password").unwrap();
+        bob.accept_share(bob_share).unwrap();
+        bob.close().unwrap();
+
+        // Bob uploads a tree holding a copy of the shared folder, under its id
+        let finish = prove_password(&mut server, b"client", b"Bob", b"password").unwrap();
+        let response = server.login_finish(finish).unwrap();
+        let alice = server.get_user(b"Alice").unwrap().id;
+        let home = server.owner_folder(alice, server.shares.get_by_id(bob_share).unwrap().envelope.folder_id).unwrap().duplicate();
+        let home_id = home.id;
+        let etag = response.enc_root_folder.etag().unwrap();
+        let mut planted = response.enc_root_folder.duplicate();
+        planted.folders.push(home.duplicate());
+        let rejected = server.put_root_folder(&response.token, planted, etag).err();
+        assert!(matches!(rejected, Some(SafeStoreError::Malformed(_))), "{:?}", rejected);
+
+        // Nor can Bob act as the owner of the folder
+        let carol = server.get_user(b"Carol").unwrap().id;
+        let revoke = server.revoke_share(&response.token, home_id, carol).err();
+        assert!(matches!(revoke, Some(SafeStoreError::PermissionDenied)), "{:?}", revoke);
+        let home_etag = home.etag().unwrap();
+        let put = server.put_folder(&response.token, home, home_etag).err();
+        assert!(matches!(put, Some(SafeStoreError::PermissionDenied)), "{:?}", put);
+        assert_eq!(server.shares.iter().filter(|share| share.owner == alice).count(), 2);
+    }
+
+    #[test]
+    fn unknown_user_gets_stable_salt_and_policy_params() {
+        let mut server = server_with_alice();
+        server.kdf_policy = KdfParams { m_cost: 16, t_cost: 2, p_cost: 1 };
+        let (salt, kdf_params) = server.get_password_salt(b"Mallory".to_vec()).unwrap();
+        let (again, _) = server.get_password_salt(b"Mallory".to_vec()).unwrap();
+        assert_eq!(salt.as_str(), again.as_str());
+        assert_eq!(kdf_params, server.kdf_policy);
+        let (other, _) = server.get_password_salt(b"Eve".to_vec()).unwrap();
+        assert_ne!(salt.as_str(), other.as_str());
+        let (alice_salt, _) = server.get_password_salt(b"Alice".to_vec()).unwrap();
+        assert_ne!(salt.as_str(), alice_salt.as_str());
+    }
+}